@@ -3,18 +3,27 @@
 mod state;
 mod constants;
 mod map;
+mod sound;
+mod text;
 
-use core::{arch::wasm32, panic::PanicInfo};
-use core::f32::consts::{PI};
-use libm::{cosf, floorf, sinf};
-use crate::constants::SCREEN_SIZE;
-use crate::map::{read_map, Orientation, Terrain, MAP_HEIGHT, MAP_WIDTH, TILE_SIZE};
-use crate::state::{State, View};
+use core::{arch::wasm32, ops::Range, panic::PanicInfo, ptr::addr_of_mut};
+use core::f32::consts::{FRAC_PI_2, PI};
+use libm::{ceilf, cosf, fabsf, floorf, sinf, sqrtf};
+use crate::constants::{FRAME_RATE, SCREEN_SIZE};
+use crate::map::{Key, Map, Orientation, Terrain, MAP_HEIGHT, MAP_WIDTH};
+use crate::sound::{Mixer, Music, Sounds};
+use crate::text::{Language, Text, TextBuffer};
+use crate::state::{
+    Controls, Debug, Inventory, Minimap, Ray, Setting, Sky, Speed, State, Stats, View, Viewport, Zoom, HUD_HEIGHT,
+    MAX_HEALTH, TRANSITION_TIME, VIEW_HEIGHT,
+};
 
 static mut PALETTE: *mut [u32; 4] = 0x04 as *mut [u32; 4];
 
 const GAMEPAD1: *const u8 = 0x16 as *const u8;
+const GAMEPAD2: *const u8 = 0x17 as *const u8;
 const DRAW_COLORS: *mut u16 = 0x14 as *mut u16;
+const FRAMEBUFFER: *mut [u8; 6400] = 0xa0 as *mut [u8; 6400];
 
 const BUTTON_LEFT: u8 = 16;  // 00010000
 const BUTTON_RIGHT: u8 = 32; // 00100000
@@ -23,6 +32,84 @@ const BUTTON_DOWN: u8 = 128; // 10000000
 const BUTTON_SPACE: u8 = 1; // 00000001
 const BUTTON_Z: u8 = 2; // 00000010
 
+
+// Delane av skjermen førstepersonsvisingane fyller, åleine over statuslinja eller to over kvarandre
+const FULL_VIEW: Viewport = Viewport { top: 0, height: VIEW_HEIGHT };
+const FULL_SCREEN: Viewport = Viewport { top: 0, height: SCREEN_SIZE };
+const TOP_HALF: Viewport = Viewport { top: 0, height: SCREEN_SIZE / 2 };
+const BOTTOM_HALF: Viewport = Viewport { top: SCREEN_SIZE as i32 / 2, height: SCREEN_SIZE / 2 };
+
+const PLAYER1_START: (f32, f32) = (1.5, 1.5); // Ruta spelar 1 byrjar i
+const PLAYER2_START: (f32, f32) = (2.5, 1.5); // Ruta spelar 2 byrjar i, ved sida av spelar 1
+const START_ANGLE: f32 = -PI / 2_f32; // Retninga begge spelarane ser i ved start
+
+const BLIT_1BPP: u32 = 0;
+
+// Terskelverdiar for ordna spreiing (dithering) i eit 4x4-mønster
+const BAYER: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+// Nøkkel med handtak øvst og tenner nedst, ein byte per rad
+const KEY_SPRITE: [u8; 8] = [
+    0b00111000,
+    0b01000100,
+    0b01000100,
+    0b00111000,
+    0b00010000,
+    0b00011000,
+    0b00010000,
+    0b00011000,
+];
+const KEY_SIZE: f32 = 0.25; // Storleiken på nøklane i høve til veggane
+const PLAYER_SPRITE: [u8; 8] = [
+    0b00111100,
+    0b00111100,
+    0b00011000,
+    0b01111110,
+    0b10111101,
+    0b00111100,
+    0b00100100,
+    0b01100110,
+];
+const PLAYER_SIZE: f32 = 0.6; // Høgda til spelaren i ein spegel i høve til veggane
+const LAMP_SPRITE: [u8; 8] = [
+    0b00011000,
+    0b00011000,
+    0b00011000,
+    0b00111100,
+    0b01111110,
+    0b11111111,
+    0b00111100,
+    0b00011000,
+];
+const LAMP_SIZE: f32 = 0.3; // Storleiken på taklampene i høve til veggane
+const SPRITE_NEAR: f32 = 0.2; // Figurar nærare enn dette, i ruter, vert ikkje teikna
+const MINIMAP_X: i32 = 108; // Venstre kant av minikartet, oppe til høgre på skjermen
+const MINIMAP_Y: i32 = 4; // Øvre kant av minikartet
+const MINIMAP_SIZE: u32 = 48; // Breidda og høgda til minikartet i pikslar
+const MINIMAP_TILE_SIZE: i32 = 4; // Storleiken på ei rute på minikartet i pikslar
+const DEBUG_COLUMNS: usize = 20; // Kor mange teikn som får plass på ei linje på skjermen
+
+// Høgda til fjella i pikslar over horisonten, jamt fordelt rundt heile himmelen
+const SKYLINE: [u8; 32] = [
+    6, 10, 18, 12, 8, 5, 9, 14, 22, 16, 11, 7, 4, 6, 9, 13,
+    10, 6, 8, 15, 24, 19, 12, 8, 5, 7, 12, 17, 11, 7, 5, 4,
+];
+
+// Skyer som (kvar rundt himmelen frå 0 til 1, pikslar over horisonten, halv breidd som del
+// av heile himmelen, halv høgd i pikslar)
+const CLOUDS: [(f32, f32, f32, f32); 5] = [
+    (0.05, 52.0, 0.030, 4.0),
+    (0.22, 64.0, 0.045, 5.0),
+    (0.41, 48.0, 0.025, 3.0),
+    (0.63, 58.0, 0.050, 6.0),
+    (0.84, 46.0, 0.035, 4.0),
+];
+
 // WASM-4 hjelpe-funksjonar
 fn set_colors(colors: u16) {
     unsafe { *DRAW_COLORS = colors; }
@@ -36,15 +123,38 @@ fn get_colors() -> u16 {
 extern "C" {
     fn line(x1: i32, y1: i32, x2: i32, y2: i32);
     fn vline(x: i32, y: i32, len: u32);
+    fn hline(x: i32, y: i32, len: u32);
     fn rect(x: i32, y: i32, width: u32, height: u32);
+    fn blit(sprite: *const u8, x: i32, y: i32, width: u32, height: u32, flags: u32);
     #[link_name = "oval"]
     fn extern_oval(x: i32, y: i32, width: u32, height: u32);
     #[link_name = "textUtf8"]
     fn extern_text(text: *const u8, length: usize, x: i32, y: i32);
     #[cfg(feature = "save")]
     fn diskw(dest_ptr: *const u8, size: u32);
-    #[link_name = "trace"]
-    fn extern_trace(message: *const u8);
+    #[link_name = "traceUtf8"]
+    fn extern_trace(message: *const u8, length: usize);
+    fn tone(frequency: u32, duration: u32, volume: u32, flags: u32);
+}
+
+/// Teiknar éin pikselpunkt med den første fargen i DRAW_COLORS
+fn pixel(x: i32, y: i32) {
+    if x < 0 || y < 0 || x >= SCREEN_SIZE as i32 || y >= SCREEN_SIZE as i32 {
+        return;
+    }
+    let color = (get_colors() & 0x0F) as u8;
+    if color == 0 {
+        return;
+    }
+
+    // kvar byte i biletbufferen held fire pikslar med to bit kvar
+    let index = (y as usize * SCREEN_SIZE as usize + x as usize) >> 2;
+    let shift = (x as u8 & 0b11) << 1;
+    let mask = 0b11 << shift;
+    unsafe {
+        let framebuffer = &mut *FRAMEBUFFER;
+        framebuffer[index] = (((color - 1) & 0b11) << shift) | (framebuffer[index] & !mask);
+    }
 }
 
 fn oval(x: i32, y: i32, width: u32, height: u32) {
@@ -57,7 +167,7 @@ fn text(text: &str, x: i32, y: i32) {
 
 fn trace(message: &str) {
     unsafe {
-        extern_trace(message.as_ptr())
+        extern_trace(message.as_ptr(), message.len())
     }
 }
 
@@ -73,15 +183,30 @@ fn extract_colors() -> (u16, u16) {
     (primary, secondary)
 }
 
-fn dashed_vline(x: i32, y: i32, len: u32) {
+fn dashed_vline(view: &Viewport, x: i32, y: i32, len: u32) {
     let (primary, secondary) = extract_colors();
     set_colors(primary);
     for b in (y..y + (1 - (x % 2)) + len as i32).step_by(2) {
-        unsafe { vline(x, b, 1) }
+        clipped_vline(view, x, b, b + 1);
     }
     set_colors(secondary);
     for b in (y + 1..y + (x % 2) + len as i32).step_by(2) {
-        unsafe { vline(x, b, 1) }
+        clipped_vline(view, x, b, b + 1);
+    }
+}
+
+/// Teiknar ei loddrett linje frå rada `top` til `bottom`, kutta til radene i visingsfeltet
+fn clipped_vline(view: &Viewport, x: i32, top: i32, bottom: i32) {
+    let (top, bottom) = (top.max(view.top), bottom.min(view.bottom()));
+    if top < bottom {
+        unsafe { vline(x, top, (bottom - top) as u32) }
+    }
+}
+
+/// Teiknar éin pikselpunkt om han er innanfor radene i visingsfeltet
+fn clipped_pixel(view: &Viewport, x: i32, y: i32) {
+    if y >= view.top && y < view.bottom() {
+        pixel(x, y);
     }
 }
 
@@ -106,225 +231,1189 @@ fn start() {
 // Køyrer for kvart bilete
 #[no_mangle]
 unsafe fn update() {
-    STATE.update(
-        *GAMEPAD1 & BUTTON_UP != 0,
-        *GAMEPAD1 & BUTTON_DOWN != 0,
-        *GAMEPAD1 & BUTTON_LEFT != 0,
-        *GAMEPAD1 & BUTTON_RIGHT != 0,
-        *GAMEPAD1 & BUTTON_SPACE != 0,
-    );
+    // dei statiske variablane vert lånte éin gong her, så resten av biletet brukar vanlege referansar
+    let state = &mut *addr_of_mut!(STATE);
+    let player2 = &mut *addr_of_mut!(PLAYER2);
+    let mixer = &mut *addr_of_mut!(MIXER);
+    let music = &mut *addr_of_mut!(MUSIC);
+    let winner = &mut *addr_of_mut!(WINNER);
+    let map = &mut *addr_of_mut!(MAP);
 
-    // draw the ground and sky
-    set_colors(0x44);
-    rect(0, 0, SCREEN_SIZE, SCREEN_SIZE / 2);
-    set_colors(0x33);
-    rect(0, (SCREEN_SIZE / 2) as i32, SCREEN_SIZE, SCREEN_SIZE / 2);
+    // teljarane gjeld strålane i eitt teikna bilete, same om spelet går eit steg eller ikkje
+    state.stats.reset();
+    player2.stats.reset();
 
-    set_colors(0x41);
-    match STATE.view {
-        View::FirstPerson => {
-            // Gå gjennom kvar kolonne på skjermen og teikn ein vegg ut frå sentrum
-            for (x, ray) in STATE.get_rays().iter().enumerate() {
-                let ray = ray.unwrap_or_else(|| { panic!("Ugyldig stråle!") });
-                let height = ray.wall_height();
-                let scaling_factor = height as f32 / SCREEN_SIZE as f32;
-                let wall_top = 80 - (height as i32 / 2) + floorf(STATE.player_z * 80.0 * scaling_factor) as i32;
-
-                match ray.terrain {
-                    Terrain::Wall => {
-                        match ray.orientation {
-                            Orientation::Vertical => { set_colors(0x11); },
-                            Orientation::Horizontal => { set_colors(0x22); },
-                        }
-                        vline(x as i32, wall_top, height as u32);
-                    },
-                    Terrain::Doorway => {
-                        set_colors(0x24);
-                        dashed_vline(x as i32, wall_top, height as u32);
-                    },
-                    Terrain::Open => panic!("Wall should never have Terrain::Open"),
-                }
-            }
+    // X med ned halden inne slår kryping av og på i staden for å hoppe, men ikkje for det
+    // frie kameraet, som brukar dei same knappane til å søkke
+    let pressed = *GAMEPAD1 & !state.previous_gamepad;
+    let holding_down = *GAMEPAD1 & BUTTON_DOWN != 0;
+    if holding_down && pressed & BUTTON_SPACE != 0 && !state.noclip && !matches!(state.view, View::Settings) {
+        state.crouching = !state.crouching;
+    }
+
+    // når spelet står stille, går det eitt steg fram kvar gong venstre og høgre vert
+    // trykte saman, og ingenting rører seg medan menyen eller inspektøren er open
+    let both_turns = BUTTON_LEFT | BUTTON_RIGHT;
+    let step_pressed = *GAMEPAD1 & both_turns == both_turns && state.previous_gamepad & both_turns != both_turns;
+    let frozen = matches!(state.view, View::Settings | View::RayInspector);
+    let step = state.advance(step_pressed) && !frozen;
+
+    // kartet er felles for begge spelarane, så skyveveggar flyttar seg berre éin gong per steg
+    if step {
+        map.update();
+    }
+
+    // spelet står stille medan innstillingsmenyen eller stråleinspektøren er open
+    if matches!(state.view, View::Settings) {
+        let two_players = state.two_players;
+        state.update_settings(map, pressed & BUTTON_UP != 0, pressed & BUTTON_DOWN != 0, pressed & BUTTON_SPACE != 0);
+
+        // kappløpet byrjar på nytt frå start kvar gong tospelarmodus vert slått av eller på
+        if state.two_players != two_players {
+            *map = Map::new();
+            state.restart();
+            player2.restart();
+            *winner = None;
         }
-        View::FishEyeMonochrome => {
-            // Gå gjennom kvar kolonne på skjermen og teikn ein vegg ut frå sentrum
-            for (x, ray) in STATE.get_rays().iter().enumerate() {
-                let ray = ray.unwrap_or_else(|| { panic!("Ugyldig stråle!") });
-                let height = ray.wall_height_fisheye();
-                let scaling_factor = height as f32 / SCREEN_SIZE as f32;
-                let wall_top = 80 - (height as i32 / 2) + floorf(STATE.player_z * 80.0 * scaling_factor) as i32;
-
-                match ray.terrain {
-                    Terrain::Wall => {
-                        vline(x as i32, wall_top, height as u32);
-                    },
-                    Terrain::Doorway => {
-                        set_colors(0x24);
-                        dashed_vline(x as i32, wall_top, height as u32);
-                    },
-                    Terrain::Open => panic!("Wall should never have Terrain::Open"),
-                }
-            }
+    } else if matches!(state.view, View::RayInspector) {
+        // venstre og høgre flyttar markøren éi kolonne for kvart bilete
+        if *GAMEPAD1 & BUTTON_LEFT != 0 {
+            state.inspected_column = state.inspected_column.saturating_sub(1);
         }
-        View::FirstPersonMonochrome => {
-            // Gå gjennom kvar kolonne på skjermen og teikn ein vegg ut frå sentrum
-            for (x, ray) in STATE.get_rays().iter().enumerate() {
-                let ray = ray.unwrap_or_else(|| { panic!("Ugyldig stråle!") });
-                let height = ray.wall_height();
-                let scaling_factor = height as f32 / SCREEN_SIZE as f32;
-                let wall_top = 80 - (height as i32 / 2) + floorf(STATE.player_z * 80.0 * scaling_factor) as i32;
-
-                match ray.terrain {
-                    Terrain::Wall => {
-                        vline(x as i32, wall_top, height as u32);
-                    },
-                    Terrain::Doorway => {
-                        set_colors(0x24);
-                        dashed_vline(x as i32, wall_top, height as u32);
-                    },
-                    Terrain::Open => panic!("Wall should never have Terrain::Open"),
-                }
-            }
+        if *GAMEPAD1 & BUTTON_RIGHT != 0 {
+            state.inspected_column = (state.inspected_column + 1).min(SCREEN_SIZE as usize - 1);
         }
+    } else if step {
+        let up = *GAMEPAD1 & BUTTON_UP != 0;
+        let holding_x = *GAMEPAD1 & BUTTON_SPACE != 0;
+        if state.noclip {
+            // med X halden inne stig og søkk det frie kameraet med opp og ned i staden for å flytte seg
+            state.update(map, Controls {
+                up: up && !holding_x,
+                down: holding_down && !holding_x,
+                left: *GAMEPAD1 & BUTTON_LEFT != 0,
+                right: *GAMEPAD1 & BUTTON_RIGHT != 0,
+                jump: up && holding_x,
+                crouch: holding_down && holding_x,
+            });
+        } else {
+            state.update(map, Controls {
+                up,
+                down: holding_down,
+                left: *GAMEPAD1 & BUTTON_LEFT != 0,
+                right: *GAMEPAD1 & BUTTON_RIGHT != 0,
+                jump: holding_x && !holding_down,
+                crouch: state.crouching,
+            });
+        }
+
+        // spelar 2 går i same labyrint og opnar dørene og tek nøklane i det same kartet
+        if state.two_players {
+            player2.update(map, Controls {
+                up: *GAMEPAD2 & BUTTON_UP != 0,
+                down: *GAMEPAD2 & BUTTON_DOWN != 0,
+                left: *GAMEPAD2 & BUTTON_LEFT != 0,
+                right: *GAMEPAD2 & BUTTON_RIGHT != 0,
+                jump: *GAMEPAD2 & BUTTON_SPACE != 0,
+                crouch: *GAMEPAD2 & BUTTON_Z != 0,
+            });
+        }
+    }
+
+    // i tospelarmodus ser kvar spelar den andre, og den første til utgangen vinn
+    if state.two_players {
+        state.viewport = TOP_HALF;
+        state.rival = Some((player2.player_x, player2.player_y, player2.player_z));
+        player2.rival = Some((state.player_x, state.player_y, state.player_z));
+        player2.sky = state.sky;
+        player2.head_bob = state.head_bob;
+        if winner.is_none() {
+            *winner = if state.completed { Some(1) } else if player2.completed { Some(2) } else { None };
+        }
+    } else {
+        state.viewport = FULL_VIEW;
+        state.rival = None;
+    }
+
+    // spel lydane frå dette biletet på kanalane som er ledige for dei
+    mixer.update();
+    for sound in state.sounds.take().chain(player2.sounds.take()) {
+        if let Some(note) = mixer.play(sound) {
+            tone(note.frequency, note.duration, note.volume, note.flags);
+        }
+    }
+    if let Some(cue) = state.exit_cue(map).filter(|_| step) {
+        if let Some(note) = mixer.play_cue(cue) {
+            tone(note.frequency, note.duration, note.volume, note.flags);
+        }
+    }
+    if state.music {
+        for note in music.update(mixer).into_iter().flatten() {
+            tone(note.frequency, note.duration, note.volume, note.flags);
+        }
+    }
+
+    match state.view {
+        View::FirstPerson => draw_first_person_views(map, state, player2, false, false),
+        View::FishEyeMonochrome => draw_first_person_views(map, state, player2, true, true),
+        View::FirstPersonMonochrome => draw_first_person_views(map, state, player2, false, true),
+        View::Settings => draw_settings(state),
+        View::RayInspector => draw_ray_inspector(map, state),
         View::Map => {
+            let camera = MapCamera::following(state);
             set_colors(0x11);
             rect(0, 0, SCREEN_SIZE, SCREEN_SIZE);
 
             // draw cells
-            for y in 0..MAP_HEIGHT as i32 {
-                for x in 0..MAP_WIDTH as i32 {
-                    if read_map(x as f32, y as f32) == Terrain::Wall {
-                        set_colors(0x22);
-                    } else {
-                        set_colors(0x33);
-                    }
+            for y in camera.visible_rows() {
+                for x in camera.visible_columns() {
+                    set_colors(tile_colors(map.read_tile(x as f32, y as f32)));
 
-                    rect(
-                        x * TILE_SIZE + (TILE_SIZE / 2),
-                        y * TILE_SIZE + (TILE_SIZE / 2),
-                        TILE_SIZE as u32,
-                        TILE_SIZE as u32,
-                    );
+                    camera.draw_tile(x, y);
                 }
             }
+            camera.draw_segments(map);
+            camera.draw_keys(map);
+            camera.draw_push_wall(map);
 
             // draw player
             set_colors(0x44);
-            let jump_height = floorf(STATE.player_z * 3.0) as u32;
-            let jump_perturbation = floorf(STATE.player_z * 1.5) as i32;
+            let jump_height = floorf(state.player_z * 3.0) as u32;
+            let jump_perturbation = floorf(state.player_z * 1.5) as i32;
             oval(
-                to_map(STATE.player_x) - 3 - jump_perturbation,
-                to_map(STATE.player_y) - 3 - jump_perturbation,
+                camera.to_map_x(state.player_x) - 3 - jump_perturbation,
+                camera.to_map_y(state.player_y) - 3 - jump_perturbation,
                 6 + jump_height,
                 6 + jump_height
             );
             oval(
-                to_map(STATE.player_x + sinf(STATE.player_angle + PI / 2_f32)) - 2,
-                to_map(STATE.player_y + cosf(STATE.player_angle + PI / 2_f32)) - 2,
+                camera.to_map_x(state.player_x + sinf(state.player_angle + PI / 2_f32)) - 2,
+                camera.to_map_y(state.player_y + cosf(state.player_angle + PI / 2_f32)) - 2,
                 3,
                 3,
             );
         },
 
         View::MapWithRays => {
+            let camera = MapCamera::following(state);
             set_colors(0x11);
             rect(0, 0, SCREEN_SIZE, SCREEN_SIZE);
 
             // draw walls
             set_colors(0x22);
-            for y in 0..MAP_HEIGHT as i32 {
-                for x in 0..MAP_WIDTH as i32 {
-                    if read_map(x as f32, y as f32) != Terrain::Wall {
+            for y in camera.visible_rows() {
+                for x in camera.visible_columns() {
+                    if !matches!(map.read_tile(x as f32, y as f32), Terrain::Wall | Terrain::PushWall) {
                         continue
                     }
 
-                    rect(
-                        x * TILE_SIZE + (TILE_SIZE / 2),
-                        y * TILE_SIZE + (TILE_SIZE / 2),
-                        TILE_SIZE as u32,
-                        TILE_SIZE as u32,
-                    );
+                    camera.draw_tile(x, y);
                 }
             }
+            camera.draw_segments(map);
 
             // draw player
             set_colors(0x44);
             oval(
-                to_map(STATE.player_x + sinf(STATE.player_angle + PI / 2_f32)) - 2,
-                to_map(STATE.player_y + cosf(STATE.player_angle + PI / 2_f32)) - 2,
+                camera.to_map_x(state.player_x + sinf(state.player_angle + PI / 2_f32)) - 2,
+                camera.to_map_y(state.player_y + cosf(state.player_angle + PI / 2_f32)) - 2,
                 3,
                 3,
             );
 
             // draw floor
-            set_colors(0x33);
-            for y in 0..MAP_HEIGHT as i32 {
-                for x in 0..MAP_WIDTH as i32 {
-                    if read_map(x as f32, y as f32) == Terrain::Wall {
+            for y in camera.visible_rows() {
+                for x in camera.visible_columns() {
+                    let terrain = map.read_tile(x as f32, y as f32);
+                    if matches!(terrain, Terrain::Wall | Terrain::PushWall) {
                         continue
                     }
 
-                    rect(
-                        x * TILE_SIZE + (TILE_SIZE / 2),
-                        y * TILE_SIZE + (TILE_SIZE / 2),
-                        TILE_SIZE as u32,
-                        TILE_SIZE as u32,
-                    );
+                    set_colors(tile_colors(terrain));
+                    camera.draw_tile(x, y);
                 }
             }
+            camera.draw_segments(map);
+            camera.draw_keys(map);
+            camera.draw_push_wall(map);
 
             // draw player
             set_colors(0x44);
 
-            let jump_height = floorf(STATE.player_z * 3.0) as u32;
-            let jump_perturbation = floorf(STATE.player_z * 1.5) as i32;
+            let jump_height = floorf(state.player_z * 3.0) as u32;
+            let jump_perturbation = floorf(state.player_z * 1.5) as i32;
             oval(
-                to_map(STATE.player_x) - 3 - jump_perturbation,
-                to_map(STATE.player_y) - 3 - jump_perturbation,
+                camera.to_map_x(state.player_x) - 3 - jump_perturbation,
+                camera.to_map_y(state.player_y) - 3 - jump_perturbation,
                 6 + jump_height,
                 6 + jump_height
             );
 
-            // Gå gjennom kvar kolonne på skjermen og teikn ein vegg ut frå sentrum
-            for ray in STATE.get_rays().iter().step_by(20) {
-                let ray = ray.unwrap_or_else(|| { panic!("Ugyldig stråle!") });
+            // Gå gjennom kvar kolonne på skjermen og teikn strålen fram til der han stoggar
+            for column in state.get_rays(map).step_by(20) {
+                let Some(ray) = column.hits().last().or(column.outside.as_ref()) else {
+                    continue;
+                };
+
+                let x2 = camera.to_map_x(state.player_x + ray.distance * sinf(state.player_angle + ray.angle_diff + PI / 2_f32));
+                let y2 = camera.to_map_y(state.player_y + ray.distance * cosf(state.player_angle + ray.angle_diff + PI / 2_f32));
+
+                line(camera.to_map_x(state.player_x), camera.to_map_y(state.player_y), x2, y2);
+            }
+        }
+    }
+
+    match state.view {
+        View::FirstPerson | View::FirstPersonMonochrome | View::FishEyeMonochrome if state.two_players => {
+            draw_race(state, *winner);
+        }
+        View::FirstPerson | View::FirstPersonMonochrome | View::FishEyeMonochrome => {
+            if state.minimap != Minimap::Off {
+                draw_minimap(map, state);
+            }
+            draw_hud(state);
+        }
+        View::Map | View::MapWithRays => draw_inventory(&state.inventory, 2, SCREEN_SIZE as i32 - 10),
+        View::RayInspector | View::Settings => {}
+    }
 
-                let x2 = to_map(STATE.player_x + ray.distance * sinf(STATE.player_angle + ray.angle_diff + PI / 2_f32));
-                let y2 = to_map(STATE.player_y + ray.distance * cosf(STATE.player_angle + ray.angle_diff + PI / 2_f32));
+    // i tospelarmodus flimrar berre visinga til den spelaren som vart send av garde
+    match state.view {
+        View::FirstPerson | View::FirstPersonMonochrome | View::FishEyeMonochrome if state.two_players => {
+            for player in [&*state, &*player2] {
+                if player.transition > 0.0 {
+                    draw_transition(&player.viewport, player.transition / TRANSITION_TIME);
+                }
+            }
+        }
+        _ if state.transition > 0.0 => draw_transition(&FULL_SCREEN, state.transition / TRANSITION_TIME),
+        _ => {}
+    }
 
-                line(to_map(STATE.player_x), to_map(STATE.player_y), x2, y2);
+    // loggen vert skriven éin gong i sekundet så han ikkje fløymer over
+    match state.debug {
+        Debug::Overlay if !matches!(state.view, View::Settings | View::RayInspector) => draw_debug(map, state),
+        Debug::Trace if state.frames % FRAME_RATE == 0 => {
+            for line in debug_lines(map, state) {
+                trace(line.as_str());
             }
         }
+        _ => {}
     }
 
     // toggle game view
     unsafe {
-        if (*GAMEPAD1 & (*GAMEPAD1 ^ STATE.previous_gamepad)) & BUTTON_Z != 0 {
-            STATE.view = match &STATE.view {
+        if pressed & BUTTON_Z != 0 {
+            state.view = match &state.view {
                 View::Map => View::MapWithRays,
-                View::MapWithRays => View::FishEyeMonochrome,
+                View::MapWithRays => View::RayInspector,
+                View::RayInspector => View::FishEyeMonochrome,
                 View::FishEyeMonochrome => View::FirstPersonMonochrome,
                 View::FirstPersonMonochrome => View::FirstPerson,
-                View::FirstPerson => View::Map,
+                View::FirstPerson => View::Settings,
+                View::Settings => View::Map,
+            };
+        }
+
+        state.previous_gamepad = *GAMEPAD1;
+    }
+}
+
+/// Kameraet som avgjer kva del av kartet som vert teikna på skjermen
+struct MapCamera {
+    left: f32,
+    top: f32,
+    tile_size: i32,
+}
+
+impl MapCamera {
+    /// Lagar eit kamera som følgjer spelaren utan å rulle forbi kanten av kartet
+    fn following(state: &State) -> MapCamera {
+        let tile_size = state.map_zoom.tile_size();
+        let visible_tiles = SCREEN_SIZE as f32 / tile_size as f32;
+        MapCamera {
+            left: follow(state.player_x, MAP_WIDTH as f32, visible_tiles),
+            top: follow(state.player_y, MAP_HEIGHT as f32, visible_tiles),
+            tile_size,
+        }
+    }
+
+    fn to_map_x(&self, position: f32) -> i32 {
+        floorf((position - self.left) * self.tile_size as f32) as i32
+    }
+
+    fn to_map_y(&self, position: f32) -> i32 {
+        floorf((position - self.top) * self.tile_size as f32) as i32
+    }
+
+    /// Kolonnane på kartet som er synlege på skjermen
+    fn visible_columns(&self) -> Range<i32> {
+        visible_range(self.left, self.tile_size, MAP_WIDTH)
+    }
+
+    /// Radene på kartet som er synlege på skjermen
+    fn visible_rows(&self) -> Range<i32> {
+        visible_range(self.top, self.tile_size, MAP_HEIGHT)
+    }
+
+    /// Teiknar nøklane som ligg på golvet som små ruter i nøkkelfargen
+    fn draw_keys(&self, map: &Map) {
+        for y in self.visible_rows() {
+            for x in self.visible_columns() {
+                if let Terrain::Key(key) = map.read_map(x as f32, y as f32) {
+                    set_colors(key_colors(key));
+                    let offset = self.tile_size / 4;
+                    unsafe {
+                        rect(
+                            self.to_map_x(x as f32) + offset,
+                            self.to_map_y(y as f32) + offset,
+                            (self.tile_size - 2 * offset) as u32,
+                            (self.tile_size - 2 * offset) as u32,
+                        )
+                    }
+                }
+            }
+        }
+    }
+
+    /// Teiknar ein skyvevegg i rørsle der han står no, mellom rutene
+    fn draw_push_wall(&self, map: &Map) {
+        let Some(push_wall) = map.push_wall else { return };
+        let (left, top, _, _) = push_wall.bounds();
+        set_colors(tile_colors(Terrain::PushWall));
+        unsafe {
+            rect(
+                self.to_map_x(left),
+                self.to_map_y(top),
+                self.tile_size as u32,
+                self.tile_size as u32,
+            )
+        }
+    }
+
+    /// Teiknar tynne og skrå veggar som strekar over rutene sine
+    fn draw_segments(&self, map: &Map) {
+        set_colors(0x22);
+        for y in self.visible_rows() {
+            for x in self.visible_columns() {
+                if let Some(segment) = map.segment(x as f32, y as f32) {
+                    unsafe {
+                        line(
+                            self.to_map_x(segment.x0),
+                            self.to_map_y(segment.y0),
+                            self.to_map_x(segment.x1),
+                            self.to_map_y(segment.y1),
+                        )
+                    }
+                }
+            }
+        }
+    }
+
+    fn draw_tile(&self, x: i32, y: i32) {
+        unsafe {
+            rect(
+                self.to_map_x(x as f32),
+                self.to_map_y(y as f32),
+                self.tile_size as u32,
+                self.tile_size as u32,
+            )
+        }
+    }
+}
+
+/// Finn kvar kameraet skal starte langs ein akse: sentrert om heile kartet får plass,
+/// elles rundt spelaren men avgrensa av kanten av kartet
+fn follow(position: f32, map_length: f32, visible_tiles: f32) -> f32 {
+    if map_length <= visible_tiles {
+        (map_length - visible_tiles) / 2.0
+    } else {
+        (position - visible_tiles / 2.0).clamp(0.0, map_length - visible_tiles)
+    }
+}
+
+fn visible_range(start: f32, tile_size: i32, map_length: usize) -> Range<i32> {
+    let end = start + SCREEN_SIZE as f32 / tile_size as f32;
+    (floorf(start) as i32).max(0)..(ceilf(end) as i32).min(map_length as i32)
+}
+
+/// Teiknar førstepersonsvisinga til spelar 1, eller til begge spelarane over kvarandre i
+/// tospelarmodus
+fn draw_first_person_views(map: &Map, player1: &State, player2: &State, fisheye: bool, monochrome: bool) {
+    // kvar visning held seg innanfor sitt eige visingsfelt
+    draw_first_person(map, player1, fisheye, monochrome);
+    if player1.two_players {
+        draw_first_person(map, player2, fisheye, monochrome);
+    }
+}
+
+/// Teiknar bakken, veggane og figurane slik spelaren ser dei, i visingsfeltet hans
+fn draw_first_person(map: &Map, state: &State, fisheye: bool, monochrome: bool) {
+    // bakken; himmelen vert teikna kolonne for kolonne over veggane
+    let view = &state.viewport;
+    let ground = (state.horizon() as i32).max(view.top);
+    set_colors(0x33);
+    unsafe { rect(0, ground, SCREEN_SIZE, (view.bottom() - ground).max(0) as u32) }
+
+    set_colors(0x41);
+    let depth = draw_walls(map, state, fisheye, monochrome);
+    draw_sprites(map, state, &depth, fisheye);
+}
+
+/// Teiknar skiljelinja mellom dei to visingane og kven som vann når ein av spelarane har
+/// nådd utgangen
+fn draw_race(state: &State, winner: Option<i32>) {
+    set_colors(0x11);
+    unsafe { rect(0, BOTTOM_HALF.top - 1, SCREEN_SIZE, 2) }
+
+    let Some(winner) = winner else { return };
+    let language = state.language;
+    let mut message = TextBuffer::<24>::new();
+    message.push_str(language.get(Text::Player)).push_int(winner).push_str(language.get(Text::Wins));
+
+    let width = 8 * message.as_str().chars().count() as i32;
+    let x = (SCREEN_SIZE as i32 - width) / 2;
+    set_colors(0x11);
+    unsafe { rect(x - 2, BOTTOM_HALF.top - 6, width as u32 + 4, 12) }
+    set_colors(0x04);
+    text(message.as_str(), x, BOTTOM_HALF.top - 4);
+}
+
+/// Teiknar veggane i kvar kolonne bak-fram, slik at låge veggar berre dekkjer den nedre
+/// delen av det som står bak dei, og gjev tilbake avstanden til næraste vegg i kvar kolonne
+fn draw_walls(map: &Map, state: &State, fisheye: bool, monochrome: bool) -> [f32; SCREEN_SIZE as usize] {
+    let mut depth = [f32::INFINITY; SCREEN_SIZE as usize];
+    let view = &state.viewport;
+    let eye = state.eye_height();
+    let horizon = state.horizon();
+
+    // Gå gjennom kvar kolonne på skjermen og teikn veggane ut frå sentrum
+    for (x, column) in state.get_rays(map).enumerate() {
+        if let Some(nearest) = column.nearest_solid() {
+            depth[x] = nearest.distance;
+        }
+        let wall_height = |ray: &Ray| if fisheye { ray.wall_height_fisheye() } else { ray.wall_height() };
+
+        // himmelen eller taket vert berre teikna over den høgaste veggen ein ikkje ser gjennom
+        let sky_bottom = column.hits()
+            .filter(|ray| !ray.terrain.is_see_through())
+            .map(|ray| ray.wall_span(wall_height(ray), eye, horizon).0)
+            .fold(horizon as i32, i32::min)
+            .max(view.top);
+        match state.sky {
+            Sky::Panorama => draw_sky(state, x, sky_bottom),
+            Sky::Ceiling => draw_ceiling(state, x, sky_bottom, fisheye),
+        }
+
+        // golvet vert mørkare langt frå fakler og lamper
+        if !monochrome {
+            let surface = state.surface(x, fisheye);
+            set_colors(0x22);
+            for y in (ceilf(horizon) as i32).max(view.top)..view.bottom() {
+                if dither(x as i32, y, 1.0 - state.floor_light(map, &surface, y)) {
+                    pixel(x as i32, y);
+                }
+            }
+        }
+
+        // utanfor kartet held bakken fram til horisonten i ein annan farge enn golvet inne
+        if let Some(outside) = column.outside {
+            let (_, edge) = outside.wall_span(wall_height(&outside), eye, horizon);
+            set_colors(0x22);
+            for y in (ceilf(horizon) as i32).max(view.top)..edge.min(view.bottom()) {
+                if dither(x as i32, y, 0.5) {
+                    pixel(x as i32, y);
+                }
+            }
+        }
+
+        // spegelbiletet av spelaren vert teikna mellom veggane bak og framfor det
+        let mut reflection = column.reflection;
+        for ray in column.hits().rev() {
+            if let Some(image) = reflection.filter(|image| image.distance > ray.distance) {
+                draw_reflection(view, x as i32, &image, wall_height(&image), eye, horizon);
+                reflection = None;
+            }
+
+            let (top, bottom) = ray.wall_span(wall_height(ray), eye, horizon);
+
+            // toppflata på veggar som er lågare enn auget, fram til der strålen går ut av ruta
+            if eye > ray.terrain.height() && !ray.terrain.is_see_through() {
+                let exit = ray.exit();
+                let (exit_top, _) = exit.wall_span(wall_height(&exit), eye, horizon);
+                set_colors(0x22);
+                clipped_vline(view, x as i32, exit_top, top);
+            }
+
+            match ray.terrain {
+                Terrain::LockedDoor(key) => set_colors(door_colors(key)),
+                _ if monochrome => set_colors(0x11),
+                _ => match ray.orientation {
+                    Orientation::Vertical => set_colors(0x11),
+                    Orientation::Horizontal => set_colors(0x22),
+                },
+            }
+            match ray.terrain {
+                Terrain::LockedDoor(_) => dashed_vline(view, x as i32, top, (bottom - top) as u32),
+                Terrain::LowPassage => {
+                    // framsida av taket heng ned til takhøgda, og undersida er synleg når
+                    // augo er under taket
+                    let ceiling = ray.terrain.ceiling();
+                    let lintel = bottom - (wall_height(ray) * ceiling) as i32;
+                    if !monochrome {
+                        set_colors(0x22);
+                    }
+                    clipped_vline(view, x as i32, top, lintel);
+                    if eye < ceiling {
+                        let exit = ray.exit();
+                        let (_, exit_bottom) = exit.wall_span(wall_height(&exit), eye, horizon);
+                        let exit_lintel = exit_bottom - (wall_height(&exit) * ceiling) as i32;
+                        set_colors(0x11);
+                        clipped_vline(view, x as i32, lintel, exit_lintel);
+                    }
+                },
+                Terrain::Doorway | Terrain::Window | Terrain::Bars | Terrain::Fence | Terrain::Mirror => {
+                    draw_see_through(view, x as i32, top, bottom, ray)
+                },
+                _ if monochrome => clipped_vline(view, x as i32, top, bottom),
+                _ => {
+                    // veggar som vender mot aust og vest er mørkare enn dei andre
+                    let level = match ray.orientation {
+                        Orientation::Vertical => ray.light * 0.7,
+                        Orientation::Horizontal => ray.light,
+                    };
+                    shaded_vline(view, x as i32, top, bottom, level);
+                    if ray.terrain == Terrain::Torch {
+                        draw_torch(view, x as i32, top, bottom, ray, state.flicker);
+                    }
+                },
+            }
+        }
+        if let Some(image) = reflection {
+            draw_reflection(view, x as i32, &image, wall_height(&image), eye, horizon);
+        }
+    }
+
+    depth
+}
+
+/// Teiknar himmelen i kolonna `x` ned til rada `bottom`, med fjell og skyer som flyttar
+/// seg når spelaren snur seg og følgjer horisonten opp og ned
+fn draw_sky(state: &State, x: usize, bottom: i32) {
+    let view = &state.viewport;
+    set_colors(0x44);
+    clipped_vline(view, x as i32, view.top, bottom);
+
+    // around er kvar rundt himmelen kolonna ser, frå 0 til 1
+    let turns = (state.player_angle + state.angle_diff(x)) / (2.0 * PI);
+    let around = turns - floorf(turns);
+    let horizon = state.horizon();
+
+    // fjellhøgda mellom to punkt på SKYLINE
+    let position = around * SKYLINE.len() as f32;
+    let index = position as usize % SKYLINE.len();
+    let next = SKYLINE[(index + 1) % SKYLINE.len()] as f32;
+    let fraction = position - floorf(position);
+    let mountain = SKYLINE[index] as f32 * (1.0 - fraction) + next * fraction;
+    let mountain_top = (horizon - mountain) as i32;
+    set_colors(0x22);
+    clipped_vline(view, x as i32, mountain_top, bottom);
+
+    // skyene er ellipsar, og delen av kvar sky som er i kolonna vert teikna
+    set_colors(0x33);
+    for (centre, height, half_width, half_height) in CLOUDS {
+        let mut across = around - centre;
+        if across > 0.5 {
+            across -= 1.0;
+        } else if across < -0.5 {
+            across += 1.0;
+        }
+        let across = across / half_width;
+        if fabsf(across) >= 1.0 {
+            continue;
+        }
+
+        let extent = half_height * sqrtf(1.0 - across * across);
+        let top = (horizon - height - extent) as i32;
+        let cloud_bottom = ((horizon - height + extent) as i32).min(bottom);
+        clipped_vline(view, x as i32, top, cloud_bottom);
+    }
+}
+
+/// Teiknar eit tak med bjelkar langs kantane av rutene i kolonna `x` ned til rada `bottom`
+fn draw_ceiling(state: &State, x: usize, bottom: i32, fisheye: bool) {
+    let surface = state.surface(x, fisheye);
+    for y in state.viewport.top..bottom {
+        let (map_x, map_y) = state.surface_point(&surface, y);
+        let beam = map_x - floorf(map_x) < 0.1 || map_y - floorf(map_y) < 0.1;
+        set_colors(if beam { 0x11 } else { 0x22 });
+        pixel(x as i32, y);
+    }
+}
+
+/// Teiknar ei loddrett linje som går frå mørk til lys etter `level` mellom 0 og 1,
+/// ved å blande to nabofargar i paletten
+fn shaded_vline(view: &Viewport, x: i32, top: i32, bottom: i32, level: f32) {
+    // dei lysaste veggane vert ikkje heilt like lyse som golvet
+    let shade = level.clamp(0.0, 1.0) * 1.5;
+    let (dark, light, mix) = if shade < 1.0 { (0x11, 0x22, shade) } else { (0x22, 0x33, shade - 1.0) };
+    set_colors(dark);
+    clipped_vline(view, x, top, bottom);
+    set_colors(light);
+    for y in top.max(view.top)..bottom.min(view.bottom()) {
+        if dither(x, y, mix) {
+            pixel(x, y);
+        }
+    }
+}
+
+/// Teiknar flammen på ein vegg med fakkel, som blafrar med `flicker`
+fn draw_torch(view: &Viewport, x: i32, top: i32, bottom: i32, ray: &Ray, flicker: f32) {
+    if ray.offset < 0.42 || ray.offset > 0.58 {
+        return;
+    }
+    let length = (bottom - top) as f32;
+    let flame_top = top + (length * (0.5 - 0.15 * flicker)) as i32;
+    let flame_bottom = top + (length * 0.45) as i32;
+    set_colors(0x33);
+    clipped_vline(view, x, flame_top, flame_bottom.max(flame_top + 1));
+    set_colors(0x11);
+    clipped_vline(view, x, flame_bottom, flame_bottom + (length * 0.1) as i32);
+}
+
+/// Teiknar ei kolonne av ei rute ein kan sjå gjennom oppå det som allereie er teikna bak ho
+fn draw_see_through(view: &Viewport, x: i32, top: i32, bottom: i32, ray: &Ray) {
+    let length = bottom - top;
+    let (visible_top, visible_bottom) = (top.max(view.top), bottom.min(view.bottom()));
+    match ray.terrain {
+        // døropningar har karmar på sidene og ein overligger, og er elles opne
+        Terrain::Doorway => {
+            if ray.offset < 0.12 || ray.offset > 0.88 {
+                clipped_vline(view, x, top, bottom);
+            } else {
+                clipped_vline(view, x, top, top + (length / 6).max(1));
+            }
+        },
+        // speglar har ei tynn ramme, og biletet i dei er litt mørkare enn det dei speglar
+        Terrain::Mirror => {
+            if ray.offset < 0.04 || ray.offset > 0.96 {
+                clipped_vline(view, x, top, bottom);
+            } else {
+                set_colors(0x11);
+                for y in visible_top..visible_bottom {
+                    if dither(x, y, 0.2) {
+                        pixel(x, y);
+                    }
+                }
+            }
+        },
+        // vindauge har ei tett ramme og glas som berre så vidt farger det som er bak
+        Terrain::Window => {
+            if ray.offset < 0.08 || ray.offset > 0.92 {
+                clipped_vline(view, x, top, bottom);
+            } else {
+                set_colors(0x44);
+                for y in visible_top..visible_bottom {
+                    if dither(x, y, 0.25) {
+                        pixel(x, y);
+                    }
+                }
+            }
+        },
+        // gitter er loddrette stenger med ope mellom
+        Terrain::Bars => {
+            if (ray.offset * 5.0) % 1.0 < 0.3 {
+                clipped_vline(view, x, top, bottom);
+            }
+        },
+        // gjerde har ei tett øvre list og rutenett under
+        Terrain::Fence => {
+            let rail = (length / 6).max(1);
+            clipped_vline(view, x, top, top + rail);
+            for y in (top + rail).max(visible_top)..visible_bottom {
+                if (x + y) % 3 == 0 {
+                    pixel(x, y);
+                }
+            }
+        },
+        _ => clipped_vline(view, x, top, bottom),
+    }
+}
+
+/// Teiknar spelaren slik han syner att i ein spegel, i kolonna `x` som spegelbiletet fyller
+fn draw_reflection(view: &Viewport, x: i32, image: &Ray, height: f32, eye: f32, horizon: f32) {
+    // spelaren står på golvet, men er like høgt oppe som augo når han hoppar
+    let (_, floor) = image.wall_span(height, eye, horizon);
+    let lift = height * (eye - 0.5);
+    let sprite_size = height * PLAYER_SIZE;
+    let sprite_top = floor - (lift + sprite_size) as i32;
+
+    let u = (image.offset * 8.0) as usize;
+    set_colors(0x44);
+    for row in 0..sprite_size as i32 {
+        let v = (row as f32 / sprite_size * 8.0) as usize;
+        if u < 8 && v < 8 && PLAYER_SPRITE[v] & (0x80 >> u) != 0 {
+            clipped_pixel(view, x, sprite_top + row);
+        }
+    }
+}
+
+/// Fargane ei rute skal teiknast med på karta
+fn tile_colors(terrain: Terrain) -> u16 {
+    match terrain {
+        Terrain::Wall | Terrain::PushWall | Terrain::Torch => 0x22,
+        Terrain::LockedDoor(key) => 0x30 | (key_colors(key) & 0x0F),
+        Terrain::Teleporter => 0x43,
+        Terrain::Mirror => 0x24,
+        Terrain::Portal => 0x44,
+        Terrain::Pillar => 0x22,
+        Terrain::HalfWall | Terrain::Railing | Terrain::LowPassage => 0x32,
+        Terrain::Window | Terrain::Bars | Terrain::Fence => 0x32,
+        _ => 0x33,
+    }
+}
+
+/// Nøkkelfargen som fyll, med eit omriss som skil grøne og svarte nøklar frå veggar og
+/// bakgrunn på kartet
+fn key_colors(key: Key) -> u16 {
+    match key {
+        Key::Blue => 0x44,
+        Key::Green => 0x42,
+        Key::Black => 0x31,
+    }
+}
+
+/// Låste dører vert teikna rutete i nøkkelfargen og golvfargen
+fn door_colors(key: Key) -> u16 {
+    ((key_colors(key) & 0x0F) << 4) | 0x03
+}
+
+/// Teiknar nøklane som ligg på golvet og lampene som heng i taket, men berre i kolonnane
+/// der ingen vegg er nærare
+fn draw_sprites(map: &Map, state: &State, depth: &[f32], fisheye: bool) {
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            let terrain = map.read_map(x as f32, y as f32);
+            if !matches!(terrain, Terrain::Key(_) | Terrain::Lamp) {
+                continue;
+            }
+
+            let ray = state.ray_towards(map, x as f32 + 0.5, y as f32 + 0.5, terrain);
+            match terrain {
+                Terrain::Key(key) => {
+                    set_colors(key_colors(key));
+                    draw_sprite(state, depth, &ray, &KEY_SPRITE, KEY_SIZE, 0.0, fisheye);
+                },
+                Terrain::Lamp => {
+                    set_colors(0x33);
+                    draw_sprite(state, depth, &ray, &LAMP_SPRITE, LAMP_SIZE, 1.0 - LAMP_SIZE, fisheye);
+                },
+                _ => {},
+            }
+        }
+    }
+
+    // den andre spelaren i tospelarmodus
+    if let Some((x, y, z)) = state.rival {
+        let ray = state.ray_towards(map, x, y, Terrain::Open);
+        set_colors(0x11);
+        draw_sprite(state, depth, &ray, &PLAYER_SPRITE, PLAYER_SIZE, z * 0.5, fisheye);
+    }
+}
+
+/// Teiknar ein figur som står `elevation` veggeiningar over golvet, skalert etter
+/// avstanden til spelaren
+fn draw_sprite(state: &State, depth: &[f32], ray: &Ray, sprite: &[u8; 8], size: f32, elevation: f32, fisheye: bool) {
+    // figurar bak spelaren, eller så nær at dei ville fylt heile skjermen, skal ikkje teiknast
+    if fabsf(ray.angle_diff) >= FRAC_PI_2 || ray.distance < SPRITE_NEAR {
+        return;
+    }
+
+    // golvet er der ein vegg i same avstand sluttar
+    let height = if fisheye { ray.wall_height_fisheye() } else { ray.wall_height() };
+    let (_, floor) = ray.wall_span(height, state.eye_height(), state.horizon());
+    let sprite_size = height * size;
+    let sprite_left = state.column(ray.angle_diff) - sprite_size / 2.0;
+    let sprite_top = floor - (height * elevation + sprite_size) as i32;
+
+    // berre kolonnane og radene som er innanfor visingsfeltet vert gått gjennom
+    let view = &state.viewport;
+    let left = floorf(sprite_left) as i32;
+    let columns = left.max(0)..(left + ceilf(sprite_size) as i32).min(SCREEN_SIZE as i32);
+    let rows = (view.top - sprite_top).max(0)..(sprite_size as i32).min(view.bottom() - sprite_top);
+    for x in columns {
+        if depth[x as usize] < ray.distance {
+            continue;
+        }
+
+        let u = ((x as f32 - sprite_left) / sprite_size * 8.0) as usize;
+        for row in rows.clone() {
+            let v = (row as f32 / sprite_size * 8.0) as usize;
+            if u < 8 && v < 8 && sprite[v] & (0x80 >> u) != 0 {
+                clipped_pixel(view, x, sprite_top + row);
+            }
+        }
+    }
+}
+
+/// Seier om pikselen (x, y) skal fyllast når ein del `level` mellom 0 og 1 av flata er dekt
+fn dither(x: i32, y: i32, level: f32) -> bool {
+    (BAYER[(y & 3) as usize][(x & 3) as usize] as f32) < level * 16.0
+}
+
+/// Dekkjer visingsfeltet med eit blått flimmer som bleiknar etter kvart som `level` går mot 0
+fn draw_transition(view: &Viewport, level: f32) {
+    set_colors(0x44);
+    for y in view.top..view.bottom() {
+        for x in 0..SCREEN_SIZE as i32 {
+            if dither(x, y, level) {
+                pixel(x, y);
+            }
+        }
+    }
+}
+
+/// Teiknar nøklane spelaren har plukka opp på rad frå (x, y)
+fn draw_inventory(inventory: &Inventory, mut x: i32, y: i32) {
+    for key in Key::ALL {
+        if !inventory.has_key(key) {
+            continue;
+        }
+
+        // 1BPP-figurar brukar den andre fargen for bit som er sett, og ein
+        // mørk nøkkel tek kantfargen så han ikkje forsvinn mot bakgrunnen
+        let colors = key_colors(key);
+        let color = if colors & 0x0F == 1 { colors >> 4 } else { colors & 0x0F };
+        set_colors(color << 4);
+        unsafe { blit(KEY_SPRITE.as_ptr(), x, y, 8, 8, BLIT_1BPP) }
+        x += 9;
+    }
+}
+
+/// Teiknar minikartet i eit hjørne, sentrert rundt spelaren og eventuelt rotert
+/// slik at spelaren alltid ser oppover
+fn draw_minimap(map: &Map, state: &State) {
+    let rotate = state.minimap == Minimap::Rotating;
+
+    // ramme rundt minikartet
+    set_colors(0x11);
+    unsafe { rect(MINIMAP_X - 1, MINIMAP_Y - 1, MINIMAP_SIZE + 2, MINIMAP_SIZE + 2) }
+
+    // forward_x og forward_y er retninga som peikar oppover på minikartet
+    let (forward_x, forward_y) = if rotate {
+        (cosf(state.player_angle), -sinf(state.player_angle))
+    } else {
+        (0.0, -1.0)
+    };
+
+    // Gå gjennom kvar piksel i minikartet og finn ruta han svarar til
+    let half_size = MINIMAP_SIZE as i32 / 2;
+    for y in 0..MINIMAP_SIZE as i32 {
+        for x in 0..MINIMAP_SIZE as i32 {
+            let right = (x - half_size) as f32 / MINIMAP_TILE_SIZE as f32;
+            let down = (y - half_size) as f32 / MINIMAP_TILE_SIZE as f32;
+            let map_x = state.player_x - right * forward_y - down * forward_x;
+            let map_y = state.player_y + right * forward_x - down * forward_y;
+
+            // tynne og skrå veggar vert teikna som ein strek gjennom ruta
+            let terrain = map.read_map(map_x, map_y);
+            match map.segment(map_x, map_y) {
+                Some(segment) if segment.distance_to(map_x, map_y) < 0.15 => set_colors(0x22),
+                _ => set_colors(tile_colors(terrain)),
+            }
+            pixel(MINIMAP_X + x, MINIMAP_Y + y);
+        }
+    }
+
+    // draw player
+    let centre_x = MINIMAP_X + half_size;
+    let centre_y = MINIMAP_Y + half_size;
+    set_colors(0x44);
+    oval(centre_x - 2, centre_y - 2, 4, 4);
+    let heading_x = if rotate { 0.0 } else { cosf(state.player_angle) };
+    let heading_y = if rotate { -1.0 } else { -sinf(state.player_angle) };
+    oval(
+        centre_x + (heading_x * MINIMAP_TILE_SIZE as f32) as i32 - 1,
+        centre_y + (heading_y * MINIMAP_TILE_SIZE as f32) as i32 - 1,
+        2,
+        2,
+    );
+}
+
+/// Teiknar statuslinja under førstepersonsvisinga med namnet på brettet, kompasset, helsa,
+/// nøklane spelaren har og tida
+fn draw_hud(state: &State) {
+    let top = VIEW_HEIGHT as i32;
+    set_colors(0x11);
+    unsafe { rect(0, top, SCREEN_SIZE, HUD_HEIGHT) }
+    set_colors(0x02);
+    unsafe { hline(0, top, SCREEN_SIZE) }
+
+    set_colors(0x03);
+    text(state.language.get(Text::LevelName), 2, top + 3);
+    draw_compass(state, 104, top + 3, 54);
+
+    // sakte film og pause vert synt nedst i førstepersonsvisinga
+    let speed = match state.speed {
+        Speed::Normal => None,
+        Speed::Slow => Some(Text::SpeedSlow),
+        Speed::Paused => Some(Text::SpeedPaused),
+    };
+    if let Some(speed) = speed {
+        set_colors(0x14);
+        text(state.language.get(speed), 2, top - 10);
+    }
+
+    // helsa er ein stolpe som vert kortare for kvar gong spelaren går inn i ein vegg
+    let width = 40;
+    set_colors(0x20);
+    unsafe { rect(2, top + 14, width + 2, 7) }
+    set_colors(0x33);
+    unsafe { rect(3, top + 15, width * state.health as u32 / MAX_HEALTH as u32, 5) }
+
+    draw_inventory(&state.inventory, 48, top + 14);
+
+    // tida sidan brettet byrja, høgrejustert
+    let mut time = TextBuffer::<8>::new();
+    time.push_time(state.ticks);
+    set_colors(0x03);
+    text(time.as_str(), SCREEN_SIZE as i32 - 2 - 8 * time.as_str().chars().count() as i32, top + 14);
+}
+
+/// Teiknar eit kompass `width` pikslar breitt der himmelretningane glir forbi når spelaren
+/// snur seg, med den spelaren ser mot i midten
+fn draw_compass(state: &State, x: i32, y: i32, width: i32) {
+    const DIRECTIONS: [(Text, f32); 4] = [(Text::North, FRAC_PI_2), (Text::East, 0.0), (Text::South, -FRAC_PI_2), (Text::West, PI)];
+
+    let centre = x + width / 2;
+    set_colors(0x02);
+    unsafe { vline(centre, y - 2, 2) }
+    unsafe { vline(centre, y + 8, 2) }
+
+    set_colors(0x03);
+    for (direction, angle) in DIRECTIONS {
+        // vinkelskilnaden vert halden innanfor (-PI, PI], og positive vinklar er til venstre
+        let mut diff = (angle - state.player_angle) % (2.0 * PI);
+        if diff > PI {
+            diff -= 2.0 * PI;
+        } else if diff <= -PI {
+            diff += 2.0 * PI;
+        }
+
+        let offset = (diff / FRAC_PI_2 * (width / 2) as f32) as i32;
+        if offset.abs() <= width / 2 - 4 {
+            text(state.language.get(direction), centre - offset - 4, y);
+        }
+    }
+}
+
+/// Teiknar kartet med strålen i den valde kolonna og krysningane han har med gitteret langs
+/// kvar gitterretning, og under kartet avstandane dei to retningane fann og kven som vann
+fn draw_ray_inspector(map: &Map, state: &State) {
+    let camera = MapCamera::following(state);
+    set_colors(0x11);
+    unsafe { rect(0, 0, SCREEN_SIZE, SCREEN_SIZE) }
+
+    for y in camera.visible_rows() {
+        for x in camera.visible_columns() {
+            set_colors(tile_colors(map.read_map(x as f32, y as f32)));
+            camera.draw_tile(x, y);
+        }
+    }
+    camera.draw_segments(map);
+
+    // strålen går fram til den næraste veggen, eller til den siste krysninga om han ikkje treff noko
+    let inspection = state.inspect(map, state.inspected_column);
+    let winner = inspection.winner();
+    let reach = inspection.horizontal.reach.max(inspection.vertical.reach);
+    let end = inspection.point(winner.map_or(reach, |(_, distance)| distance));
+    set_colors(0x04);
+    unsafe {
+        line(
+            camera.to_map_x(state.player_x),
+            camera.to_map_y(state.player_y),
+            camera.to_map_x(end.0),
+            camera.to_map_y(end.1),
+        )
+    }
+
+    // krysningar med vassrette gitterlinjer er fylte, med loddrette er berre omrisset teikna
+    for (trace, colors) in [(&inspection.horizontal, 0x33), (&inspection.vertical, 0x40)] {
+        set_colors(colors);
+        for &(x, y) in trace.crossings.iter().flatten() {
+            unsafe { rect(camera.to_map_x(x) - 1, camera.to_map_y(y) - 1, 3, 3) }
+        }
+    }
+
+    set_colors(0x44);
+    oval(camera.to_map_x(state.player_x) - 2, camera.to_map_y(state.player_y) - 2, 5, 5);
+
+    // markøren syner kva kolonne på skjermen strålen høyrer til
+    let top = VIEW_HEIGHT as i32;
+    set_colors(0x11);
+    unsafe { rect(0, top, SCREEN_SIZE, HUD_HEIGHT) }
+    set_colors(0x02);
+    unsafe { hline(0, top, SCREEN_SIZE) }
+    set_colors(0x04);
+    unsafe { vline(state.inspected_column as i32, top - 4, 4) }
+
+    let mut column = TextBuffer::<8>::new();
+    column.push_str("col ").push_int(state.inspected_column as i32);
+    set_colors(0x03);
+    text(column.as_str(), 2, top + 3);
+
+    for (i, (label, trace, orientation)) in [
+        ("H ", &inspection.horizontal, Orientation::Horizontal),
+        ("V ", &inspection.vertical, Orientation::Vertical),
+    ].into_iter().enumerate() {
+        let won = winner.is_some_and(|(winner, _)| winner == orientation);
+        let mut line = TextBuffer::<12>::new();
+        line.push_str(if won { ">" } else { " " }).push_str(label);
+        match trace.hit {
+            Some(distance) => line.push_float(distance, 2),
+            None => line.push_str("-"),
+        };
+        set_colors(if won { 0x03 } else { 0x02 });
+        text(line.as_str(), 72, top + 3 + i as i32 * 10);
+    }
+}
+
+/// Linjene med feilsøkingsinformasjon om spelaren, strålen midt på skjermen og kor mykje
+/// arbeid strålane har kravd i dette biletet
+fn debug_lines(map: &Map, state: &State) -> [TextBuffer<DEBUG_COLUMNS>; 6] {
+    let mut lines = [const { TextBuffer::new() }; 6];
+
+    lines[0].push_str("x ").push_float(state.player_x, 2).push_str(" y ").push_float(state.player_y, 2);
+    lines[1].push_str("z ").push_float(state.player_z, 2).push_str(" a ").push_float(state.player_angle, 2);
+
+    // teljarane vert lesne før midtstrålen, så han ikkje vert teken med
+    let (rays, steps) = (state.stats.rays.get(), state.stats.steps.get());
+    let centre = state.centre_column(map);
+    match centre.hits().next() {
+        Some(ray) => {
+            let orientation = match ray.orientation {
+                Orientation::Horizontal => " H ",
+                Orientation::Vertical => " V ",
             };
+            lines[2].push_str("ray ").push_float(ray.distance, 2).push_str(orientation).push_str(ray.terrain.name());
+        }
+        None => {
+            lines[2].push_str("ray -");
         }
+    }
+
+    let terrain = map.read_map(state.player_x, state.player_y);
+    lines[3]
+        .push_str("tile ")
+        .push_int(state.player_x as i32)
+        .push_str(",")
+        .push_int(state.player_y as i32)
+        .push_str(" ")
+        .push_str(terrain.name());
+    lines[4].push_str("rays ").push_int(rays as i32).push_str(" steps ").push_int(steps as i32);
+    lines[5].push_str("frame ").push_int(state.ticks as i32);
 
-        STATE.previous_gamepad = *GAMEPAD1;
+    lines
+}
+
+/// Teiknar feilsøkingslinjene oppe til venstre på ein mørk bakgrunn
+fn draw_debug(map: &Map, state: &State) {
+    for (i, line) in debug_lines(map, state).iter().enumerate() {
+        let y = 2 + i as i32 * 9;
+        set_colors(0x11);
+        unsafe { rect(0, y - 1, 8 * line.as_str().len() as u32 + 2, 9) }
+        set_colors(0x03);
+        text(line.as_str(), 1, y);
     }
 }
 
-fn to_map(position: f32) -> i32 {
-    (position * TILE_SIZE as f32) as i32 + ((TILE_SIZE / 4) * 3)
+/// Teiknar innstillingsmenyen med ei linje for kvar innstilling og ei pil ved den som er vald
+fn draw_settings(state: &State) {
+    set_colors(0x11);
+    unsafe { rect(0, 0, SCREEN_SIZE, SCREEN_SIZE) }
+
+    let language = state.language;
+    set_colors(0x04);
+    text(language.get(Text::Settings), 8, 8);
+
+    for (i, &setting) in Setting::ALL.iter().enumerate() {
+        let y = 24 + i as i32 * 11;
+        let label = language.get(setting.label());
+        let value = language.get(state.setting_value(setting));
+
+        set_colors(if i == state.setting { 0x03 } else { 0x02 });
+        if i == state.setting {
+            text(">", 8, y);
+        }
+        text(label, 20, y);
+        text(value, SCREEN_SIZE as i32 - 4 - 8 * value.chars().count() as i32, y);
+    }
+
+    set_colors(0x02);
+    text(language.get(Text::SettingsHelp), 8, 144);
 }
 
-static mut STATE: State = State {
-    view: View::Map,
-    player_x: 1.5,
-    player_y: 1.5,
-    player_z: 0.0,
-    player_velocity: 0.0,
-    player_z_velocity: 0.0,
-    player_angle: -PI / 2_f32,
-    player_angular_velocity: 0.0,
-    previous_gamepad: 0,
-};
\ No newline at end of file
+/// Tilstanden spelarane byrjar med
+const fn starting_state() -> State {
+    State {
+        view: View::Map,
+        minimap: Minimap::Off,
+        map_zoom: Zoom::Whole,
+        inventory: Inventory::new(),
+        transition: 0.0,
+        player_x: PLAYER1_START.0,
+        player_y: PLAYER1_START.1,
+        player_z: 0.0,
+        player_velocity: 0.0,
+        player_z_velocity: 0.0,
+        player_angle: START_ANGLE,
+        player_angular_velocity: 0.0,
+        previous_gamepad: 0,
+        ticks: 0,
+        flicker: 0.8,
+        head_bob: true,
+        walked: 0.0,
+        landing: 0.0,
+        shake_time: 0.0,
+        shake_strength: 0.0,
+        crouch: 0.0,
+        crouching: false,
+        sky: Sky::Panorama,
+        sounds: Sounds::new(),
+        blocked: false,
+        completed: false,
+        music: true,
+        setting: 0,
+        health: MAX_HEALTH,
+        start: (PLAYER1_START.0, PLAYER1_START.1, START_ANGLE),
+        language: Language::Nynorsk,
+        debug: Debug::Off,
+        stats: Stats::new(),
+        noclip: false,
+        noclip_origin: (0.0, 0.0),
+        speed: Speed::Normal,
+        frames: 0,
+        inspected_column: SCREEN_SIZE as usize / 2,
+        viewport: FULL_VIEW,
+        two_players: false,
+        rival: None,
+    }
+}
+
+static mut STATE: State = starting_state();
+
+// spelar 2 byrjar ved sida av spelar 1 og ser sin del av skjermen nedst
+static mut PLAYER2: State = State {
+    player_x: PLAYER2_START.0,
+    player_y: PLAYER2_START.1,
+    start: (PLAYER2_START.0, PLAYER2_START.1, START_ANGLE),
+    viewport: BOTTOM_HALF,
+    ..starting_state()
+};
+
+static mut MIXER: Mixer = Mixer::new();
+static mut MUSIC: Music = Music::new();
+static mut WINNER: Option<i32> = None;
+
+// labyrinten er felles for begge spelarane
+static mut MAP: Map = Map::new();
\ No newline at end of file
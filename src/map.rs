@@ -1,39 +1,478 @@
-use crate::constants::SCREEN_SIZE;
+use core::f32::consts::{FRAC_PI_2, PI};
+use libm::{cosf, floorf, sinf, sqrtf};
+use crate::constants::FRAME_WIDTH;
 
 pub const MAP_HEIGHT: usize = 8;
 pub const MAP_WIDTH: usize = 21;
-pub const TILE_SIZE: i32 = SCREEN_SIZE as i32 / MAP_WIDTH as i32;
 
+pub const MAX_WALL_HEIGHT: f32 = 1.5; // Høgda til dei høgaste veggane, i veggeiningar
+const PUSH_WALL_SPEED: f32 = 0.8; // Ruter per sekund
+const PUSH_WALL_REACH: usize = 2; // Kor mange ruter ein skyvevegg kan flyttast
+const THIN_WALL_INSET: f32 = 0.05; // Kor langt inn frå kanten av ruta tynne veggar står
+const AMBIENT_LIGHT: f32 = 0.4; // Lyset overalt, også langt frå fakler og lamper
+const LIGHT_RADIUS: i32 = 3; // Kor mange ruter lyset frå ei fakkel eller ein lampe når
+const MAX_LIGHT: i32 = 15; // Det høgaste lysnivået som vert lagra for kvar rute
+const LOW_CEILING: f32 = 0.5; // Høgda til taket i låge gangar, der spelaren må krype
+
+// 0 = ope, 1 = vegg, 2 = døropning, 3-5 = nøklar, 6-8 = låste dører, 9 = skyvevegg, 10 = teleportør,
+// 11 = halv vegg, 12 = søyle, 13 = rekkverk, 14 = vindauge, 15 = gitter, 16 = gjerde,
+// 17-20 = tynne veggar langs nord-, aust-, sør- og vestkanten, 21-22 = skrå veggar (/ og \),
+// 23 = spegel, 24 = portal, 25 = vegg med fakkel, 26 = taklampe, 27 = låg gang
 const MAP: [u8; MAP_HEIGHT * MAP_WIDTH] = [
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 24, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 0, 0, 0, 0, 0, 13, 4, 15, 10, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 1,
+    23, 0, 11, 11, 11, 0, 0, 0, 0, 0, 14, 12, 0, 25, 5, 1, 1, 1, 1, 1, 1,
+    23, 0, 0, 10, 25, 1, 1, 0, 12, 0, 0, 1, 0, 0, 0, 1, 21, 0, 22, 1, 1,
+    1, 0, 1, 27, 27, 0, 16, 0, 1, 1, 1, 1, 0, 1, 1, 1, 0, 1, 8, 1, 1,
+    24, 0, 1, 1, 1, 0, 1, 26, 0, 1, 1, 0, 0, 0, 26, 0, 7, 1, 0, 1, 1,
+    1, 3, 0, 0, 1, 0, 17, 0, 20, 0, 0, 0, 23, 1, 0, 1, 1, 1, 0, 6, 2,
     1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1,
-    1, 0, 1, 1, 1, 0, 0, 0, 0, 0, 1, 1, 0, 1, 0, 1, 1, 1, 1, 1, 1,
-    1, 0, 0, 0, 1, 1, 1, 0, 1, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 1,
-    1, 0, 1, 0, 0, 0, 1, 0, 1, 1, 1, 1, 0, 1, 1, 1, 0, 1, 0, 1, 1,
-    1, 0, 1, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 1, 1,
-    1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 1, 1, 1, 0, 0, 2,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
 ];
 
+/// Ein teleportør på ruta (x, y) som sender spelaren til ruta (to_x, to_y),
+/// eventuelt snudd mot ein ny vinkel
+pub struct Teleporter {
+    pub x: usize,
+    pub y: usize,
+    pub to_x: usize,
+    pub to_y: usize,
+    pub angle: Option<f32>,
+}
+
+static TELEPORTERS: [Teleporter; 2] = [
+    Teleporter { x: 3, y: 3, to_x: 9, to_y: 1, angle: Some(0.0) },
+    Teleporter { x: 9, y: 1, to_x: 3, to_y: 3, angle: None },
+];
+
+/// Ei portalside på ruta (x, y) som sender strålar og spelaren vidare ut av portalen
+/// `to` i PORTALS, med same vinkel i høve til portalen
+pub struct Portal {
+    pub x: usize,
+    pub y: usize,
+    pub side: Edge,
+    pub to: usize,
+}
+
+static PORTALS: [Portal; 2] = [
+    Portal { x: 12, y: 0, side: Edge::South, to: 1 },
+    Portal { x: 0, y: 5, side: Edge::East, to: 0 },
+];
+
+impl Portal {
+    /// Flyttar eit punkt og ein vinkel som går inn i denne portalen over til den andre
+    pub fn transform(&self, x: f32, y: f32, angle: f32) -> (f32, f32, f32) {
+        let exit = &PORTALS[self.to];
+        let (entry_x, entry_y) = self.centre();
+        let (exit_x, exit_y) = exit.centre();
+
+        // turn snur retninga inn i denne portalen til retninga ut av den andre
+        let turn = exit.side.angle() - self.side.angle() - PI;
+        let (cos, sin) = (cosf(turn), sinf(turn));
+        let (dx, dy) = (x - entry_x, y - entry_y);
+        (exit_x + dx * cos + dy * sin, exit_y - dx * sin + dy * cos, angle + turn)
+    }
+
+    /// Midtpunktet på portalsida
+    fn centre(&self) -> (f32, f32) {
+        let (normal_x, normal_y) = self.side.normal();
+        (self.x as f32 + 0.5 + normal_x * 0.5, self.y as f32 + 0.5 + normal_y * 0.5)
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Terrain {
     Open,
     Wall,
     Doorway,
+    Key(Key),
+    LockedDoor(Key),
+    PushWall,
+    Teleporter,
+    HalfWall,
+    Pillar,
+    Railing,
+    Window,
+    Bars,
+    Fence,
+    ThinWall(Edge),
+    DiagonalWall(Slant),
+    Mirror,
+    Portal,
+    Torch,
+    Lamp,
+    LowPassage,
+}
+
+impl Terrain {
+    /// Eit kort namn på terrengtypen til feilsøking
+    pub fn name(&self) -> &'static str {
+        match self {
+            Terrain::Open => "Open",
+            Terrain::Wall => "Wall",
+            Terrain::Doorway => "Doorway",
+            Terrain::Key(_) => "Key",
+            Terrain::LockedDoor(_) => "Door",
+            Terrain::PushWall => "PushWall",
+            Terrain::Teleporter => "Teleport",
+            Terrain::HalfWall => "HalfWall",
+            Terrain::Pillar => "Pillar",
+            Terrain::Railing => "Railing",
+            Terrain::Window => "Window",
+            Terrain::Bars => "Bars",
+            Terrain::Fence => "Fence",
+            Terrain::ThinWall(_) => "ThinWall",
+            Terrain::DiagonalWall(_) => "Diagonal",
+            Terrain::Mirror => "Mirror",
+            Terrain::Portal => "Portal",
+            Terrain::Torch => "Torch",
+            Terrain::Lamp => "Lamp",
+            Terrain::LowPassage => "LowPass",
+        }
+    }
+
+    /// Seier om spelaren kan gå inn i ruta utan vidare
+    pub fn is_walkable(&self) -> bool {
+        matches!(self, Terrain::Open | Terrain::Key(_) | Terrain::Teleporter | Terrain::Lamp)
+    }
+
+    /// Seier om ein kan sjå gjennom ruta til det som ligg bak
+    pub fn is_see_through(&self) -> bool {
+        matches!(self, Terrain::Doorway | Terrain::Window | Terrain::Bars | Terrain::Fence | Terrain::LowPassage)
+    }
+
+    /// Kor høgt taket i ruta er, målt i veggeiningar, der ruter ein ikkje kan gå inn i
+    /// ikkje har rom under taket i det heile
+    pub fn ceiling(&self) -> f32 {
+        match self {
+            Terrain::LowPassage => LOW_CEILING,
+            Terrain::ThinWall(_) | Terrain::DiagonalWall(_) => 1.0,
+            terrain if terrain.is_walkable() => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Kor høg ruta er, målt i veggeiningar
+    pub fn height(&self) -> f32 {
+        match self {
+            Terrain::Open | Terrain::Key(_) | Terrain::Teleporter | Terrain::Lamp => 0.0,
+            Terrain::Railing => 0.25,
+            Terrain::HalfWall | Terrain::Fence => 0.5,
+            Terrain::Pillar => MAX_WALL_HEIGHT,
+            Terrain::Wall | Terrain::Doorway | Terrain::LockedDoor(_) | Terrain::PushWall => 1.0,
+            Terrain::Window | Terrain::Bars => 1.0,
+            Terrain::ThinWall(_) | Terrain::DiagonalWall(_) | Terrain::Mirror | Terrain::Portal => 1.0,
+            Terrain::Torch | Terrain::LowPassage => 1.0,
+        }
+    }
+
+    /// Veggstykket som står inne i ruta, relativt til øvre venstre hjørne, for veggar som
+    /// ikkje fyller heile ruta
+    pub fn segment(&self) -> Option<Segment> {
+        let (x0, y0, x1, y1, orientation) = match self {
+            Terrain::ThinWall(Edge::North) => (0.0, THIN_WALL_INSET, 1.0, THIN_WALL_INSET, Orientation::Horizontal),
+            Terrain::ThinWall(Edge::South) => (0.0, 1.0 - THIN_WALL_INSET, 1.0, 1.0 - THIN_WALL_INSET, Orientation::Horizontal),
+            Terrain::ThinWall(Edge::West) => (THIN_WALL_INSET, 0.0, THIN_WALL_INSET, 1.0, Orientation::Vertical),
+            Terrain::ThinWall(Edge::East) => (1.0 - THIN_WALL_INSET, 0.0, 1.0 - THIN_WALL_INSET, 1.0, Orientation::Vertical),
+            Terrain::DiagonalWall(Slant::Rising) => (0.0, 1.0, 1.0, 0.0, Orientation::Horizontal),
+            Terrain::DiagonalWall(Slant::Falling) => (0.0, 0.0, 1.0, 1.0, Orientation::Vertical),
+            _ => return None,
+        };
+        Some(Segment { x0, y0, x1, y1, orientation })
+    }
+}
+
+/// Kanten av ruta ein tynn vegg står langs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Edge {
+    /// Retninga ut av ruta gjennom kanten
+    pub fn normal(&self) -> (f32, f32) {
+        match self {
+            Edge::North => (0.0, -1.0),
+            Edge::East => (1.0, 0.0),
+            Edge::South => (0.0, 1.0),
+            Edge::West => (-1.0, 0.0),
+        }
+    }
+
+    /// Vinkelen til retninga ut av ruta, målt som player_angle
+    pub fn angle(&self) -> f32 {
+        match self {
+            Edge::North => FRAC_PI_2,
+            Edge::East => 0.0,
+            Edge::South => -FRAC_PI_2,
+            Edge::West => PI,
+        }
+    }
+}
+
+/// Retninga ein skrå vegg går over ruta, sett ovanfrå med nord opp
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slant {
+    Rising,
+    Falling,
 }
 
+/// Eit veggstykke frå (x0, y0) til (x1, y1)
 #[derive(Clone, Copy)]
+pub struct Segment {
+    pub x0: f32,
+    pub y0: f32,
+    pub x1: f32,
+    pub y1: f32,
+    pub orientation: Orientation,
+}
+
+impl Segment {
+    /// Kvar linja frå (x, y) i retninga (dx, dy) skjer veggstykket, som (kor mange lengder
+    /// av (dx, dy) ut, kor langt inn på veggstykket frå 0 til 1)
+    pub fn intersect(&self, x: f32, y: f32, dx: f32, dy: f32) -> Option<(f32, f32)> {
+        let (ex, ey) = (self.x1 - self.x0, self.y1 - self.y0);
+        let denominator = dx * ey - dy * ex;
+        if denominator == 0.0 {
+            return None;
+        }
+
+        let (ax, ay) = (self.x0 - x, self.y0 - y);
+        let t = (ax * ey - ay * ex) / denominator;
+        let u = (ax * dy - ay * dx) / denominator;
+        (t >= 0.0 && (0.0..=1.0).contains(&u)).then_some((t, u))
+    }
+
+    /// Avstanden frå punktet (x, y) til næraste punkt på veggstykket
+    pub fn distance_to(&self, x: f32, y: f32) -> f32 {
+        let (ex, ey) = (self.x1 - self.x0, self.y1 - self.y0);
+        let u = (((x - self.x0) * ex + (y - self.y0) * ey) / (ex * ex + ey * ey)).clamp(0.0, 1.0);
+        let (nearest_x, nearest_y) = (self.x0 + ex * u, self.y0 + ey * u);
+        sqrtf((x - nearest_x) * (x - nearest_x) + (y - nearest_y) * (y - nearest_y))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    Blue,
+    Green,
+    Black,
+}
+
+impl Key {
+    pub const ALL: [Key; 3] = [Key::Blue, Key::Green, Key::Black];
+}
+
+#[derive(Clone, Copy, PartialEq)]
 pub enum Orientation {
     Horizontal,
     Vertical,
 }
 
-/// Sjekk ka som finst eit punkt på kartet
-pub fn read_map(x: f32, y: f32) -> Terrain {
-    match MAP.get((y as i32 * MAP_WIDTH as i32 + x as i32) as usize) {
-        Some(&square) if square == 0 => Terrain::Open,
-        Some(&square) if square == 1 => Terrain::Wall,
-        Some(&square) if square == 2 => Terrain::Doorway,
-        _ => Terrain::Wall,
+/// Ein skyvevegg som er på veg frå ruta si, med `offset` som er flytta så langt
+#[derive(Clone, Copy)]
+pub struct PushWall {
+    pub x: f32,
+    pub y: f32,
+    pub dx: f32,
+    pub dy: f32,
+    pub offset: f32,
+    pub distance: f32,
+}
+
+impl PushWall {
+    /// Hjørna til veggen slik han står no, som (venstre, øvst, høgre, nedst)
+    pub fn bounds(&self) -> (f32, f32, f32, f32) {
+        let left = self.x + self.dx * self.offset;
+        let top = self.y + self.dy * self.offset;
+        (left, top, left + 1.0, top + 1.0)
+    }
+
+    fn contains(&self, x: f32, y: f32) -> bool {
+        let (left, top, right, bottom) = self.bounds();
+        x >= left && x < right && y >= top && y < bottom
+    }
+}
+
+/// Reknar ut lyset i kvar rute når kartet vert lasta, med lys frå lamper i dei fire
+/// lågaste bita og lys frå fakler i dei fire høgaste
+const fn light_map(tiles: &[u8; MAP_HEIGHT * MAP_WIDTH]) -> [u8; MAP_HEIGHT * MAP_WIDTH] {
+    let mut light = [0; MAP_HEIGHT * MAP_WIDTH];
+    let mut source = 0;
+    while source < tiles.len() {
+        let shift = match tiles[source] {
+            25 => 4,
+            26 => 0,
+            _ => {
+                source += 1;
+                continue;
+            }
+        };
+
+        // lyset minkar med kvadratet av avstanden, og når ikkje lenger enn LIGHT_RADIUS
+        let radius_squared = LIGHT_RADIUS * LIGHT_RADIUS;
+        let mut index = 0;
+        while index < tiles.len() {
+            let dx = (index % MAP_WIDTH) as i32 - (source % MAP_WIDTH) as i32;
+            let dy = (index / MAP_WIDTH) as i32 - (source / MAP_WIDTH) as i32;
+            let distance_squared = dx * dx + dy * dy;
+            if distance_squared < radius_squared {
+                let added = MAX_LIGHT * (radius_squared - distance_squared) / radius_squared;
+                let total = (light[index] >> shift & 0x0F) as i32 + added;
+                let total = if total > MAX_LIGHT { MAX_LIGHT } else { total };
+                light[index] = light[index] & !(0x0F << shift) | (total as u8) << shift;
+            }
+            index += 1;
+        }
+        source += 1;
+    }
+    light
+}
+
+/// Labyrinten slik han ser ut no, med nøklar som er plukka opp og dører som er opna
+pub struct Map {
+    tiles: [u8; MAP_HEIGHT * MAP_WIDTH],
+    light: [u8; MAP_HEIGHT * MAP_WIDTH],
+    pub push_wall: Option<PushWall>,
+}
+
+impl Map {
+    pub const fn new() -> Map {
+        Map { tiles: MAP, light: light_map(&MAP), push_wall: None }
+    }
+
+    /// Kor lyst det er i ruta til punktet, frå 0 til 1, der `flicker` skalerer lyset
+    /// frå fakler slik at det flakkar
+    pub fn light(&self, x: f32, y: f32, flicker: f32) -> f32 {
+        let Some(index) = self.index(x, y) else { return AMBIENT_LIGHT };
+        let steady = (self.light[index] & 0x0F) as f32 / MAX_LIGHT as f32;
+        let flame = (self.light[index] >> 4) as f32 / MAX_LIGHT as f32;
+        (AMBIENT_LIGHT + (1.0 - AMBIENT_LIGHT) * (steady + flame * flicker)).min(1.0)
     }
-}
\ No newline at end of file
+
+    /// Sjekk ka som finst eit punkt på kartet, medrekna ein skyvevegg som er i rørsle
+    pub fn read_map(&self, x: f32, y: f32) -> Terrain {
+        match self.push_wall {
+            Some(push_wall) if push_wall.contains(x, y) => Terrain::PushWall,
+            _ => self.read_tile(x, y),
+        }
+    }
+
+    /// Sjekk ka som ligg i ruta til eit punkt, utan skyveveggar i rørsle
+    pub fn read_tile(&self, x: f32, y: f32) -> Terrain {
+        match self.index(x, y).map(|index| self.tiles[index]) {
+            Some(0) => Terrain::Open,
+            Some(1) => Terrain::Wall,
+            Some(2) => Terrain::Doorway,
+            Some(3) => Terrain::Key(Key::Blue),
+            Some(4) => Terrain::Key(Key::Green),
+            Some(5) => Terrain::Key(Key::Black),
+            Some(6) => Terrain::LockedDoor(Key::Blue),
+            Some(7) => Terrain::LockedDoor(Key::Green),
+            Some(8) => Terrain::LockedDoor(Key::Black),
+            Some(9) => Terrain::PushWall,
+            Some(10) => Terrain::Teleporter,
+            Some(11) => Terrain::HalfWall,
+            Some(12) => Terrain::Pillar,
+            Some(13) => Terrain::Railing,
+            Some(14) => Terrain::Window,
+            Some(15) => Terrain::Bars,
+            Some(16) => Terrain::Fence,
+            Some(17) => Terrain::ThinWall(Edge::North),
+            Some(18) => Terrain::ThinWall(Edge::East),
+            Some(19) => Terrain::ThinWall(Edge::South),
+            Some(20) => Terrain::ThinWall(Edge::West),
+            Some(21) => Terrain::DiagonalWall(Slant::Rising),
+            Some(22) => Terrain::DiagonalWall(Slant::Falling),
+            Some(23) => Terrain::Mirror,
+            Some(24) => Terrain::Portal,
+            Some(25) => Terrain::Torch,
+            Some(26) => Terrain::Lamp,
+            Some(27) => Terrain::LowPassage,
+            _ => Terrain::Wall,
+        }
+    }
+
+    /// Veggstykket i ruta til punktet, i kartkoordinatar
+    pub fn segment(&self, x: f32, y: f32) -> Option<Segment> {
+        let segment = self.read_tile(x, y).segment()?;
+        let (left, top) = (floorf(x), floorf(y));
+        Some(Segment {
+            x0: left + segment.x0,
+            y0: top + segment.y0,
+            x1: left + segment.x1,
+            y1: top + segment.y1,
+            orientation: segment.orientation,
+        })
+    }
+
+    /// Gjer ruta i punktet om til ope golv, til dømes når ein nøkkel vert plukka opp
+    pub fn clear(&mut self, x: f32, y: f32) {
+        self.set(x, y, 0);
+    }
+
+    /// Finn teleportøren i ruta til punktet
+    pub fn teleporter(&self, x: f32, y: f32) -> Option<&'static Teleporter> {
+        TELEPORTERS.iter().find(|teleporter| teleporter.x == x as usize && teleporter.y == y as usize)
+    }
+
+    /// Finn portalen på sida `side` av ruta til punktet
+    pub fn portal(&self, x: f32, y: f32, side: Edge) -> Option<&'static Portal> {
+        if self.read_tile(x, y) != Terrain::Portal {
+            return None;
+        }
+        PORTALS.iter().find(|portal| portal.x == x as usize && portal.y == y as usize && portal.side == side)
+    }
+
+    /// Set i gang ein skyvevegg i ruta til punktet, om han har ope golv framfor seg
+    pub fn push(&mut self, x: f32, y: f32, dx: f32, dy: f32) {
+        if self.push_wall.is_some() || self.read_tile(x, y) != Terrain::PushWall {
+            return;
+        }
+
+        // skyveveggen glir så langt det er ope golv, men ikkje lenger enn PUSH_WALL_REACH
+        let (x, y) = (floorf(x), floorf(y));
+        let distance = (1..=PUSH_WALL_REACH)
+            .take_while(|&step| self.read_tile(x + dx * step as f32, y + dy * step as f32) == Terrain::Open)
+            .count();
+        if distance == 0 {
+            return;
+        }
+
+        self.set(x, y, 0);
+        self.push_wall = Some(PushWall { x, y, dx, dy, offset: 0.0, distance: distance as f32 });
+    }
+
+    /// Flytter ein skyvevegg i rørsle og gjer han om til vanleg vegg når han er framme
+    pub fn update(&mut self) {
+        let Some(push_wall) = self.push_wall.as_mut() else { return };
+
+        push_wall.offset += PUSH_WALL_SPEED * FRAME_WIDTH;
+        if push_wall.offset >= push_wall.distance {
+            let (x, y) = (push_wall.x + push_wall.dx * push_wall.distance, push_wall.y + push_wall.dy * push_wall.distance);
+            self.push_wall = None;
+            self.set(x, y, 1);
+        }
+    }
+
+    fn set(&mut self, x: f32, y: f32, tile: u8) {
+        if let Some(index) = self.index(x, y) {
+            self.tiles[index] = tile;
+        }
+    }
+
+    /// Seier om punktet ligg innanfor kartet
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        self.index(x, y).is_some()
+    }
+
+    fn index(&self, x: f32, y: f32) -> Option<usize> {
+        // alt utanfor kartet er vegg, òg for negative koordinatar
+        if x < 0.0 || y < 0.0 || x >= MAP_WIDTH as f32 || y >= MAP_HEIGHT as f32 {
+            return None;
+        }
+        Some(y as usize * MAP_WIDTH + x as usize)
+    }
+}
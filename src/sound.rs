@@ -0,0 +1,284 @@
+use libm::exp2f;
+
+// Kanalane i WASM-4
+const PULSE1: u32 = 0;
+const PULSE2: u32 = 1;
+const TRIANGLE: u32 = 2;
+const NOISE: u32 = 3;
+
+// Pulsbreidda til pulskanalane
+const DUTY_12_5: u32 = 0;
+const DUTY_25: u32 = 1;
+const DUTY_50: u32 = 2;
+
+// Kva høyrsel ein tone vert spela i
+const PAN_LEFT: u32 = 16;
+const PAN_RIGHT: u32 = 32;
+
+const CUE_VOLUME: f32 = 40.0; // Volumet til ein stadlyd rett ved sida av spelaren
+
+const FRAMES_PER_ROW: u32 = 8; // Kor mange bilete kvar rad i eit mønster varer
+const PATTERN_LENGTH: usize = 16; // Kor mange rader det er i eit mønster
+
+// Drumlydar i trommekanalen
+const KICK: u8 = 1;
+const SNARE: u8 = 2;
+const HAT: u8 = 3;
+
+// Mønster der kvar rad er (melodi, bass, trommer); notane er MIDI-nummer og 0 er ingen ny note
+const PATTERNS: [[[u8; 3]; PATTERN_LENGTH]; 3] = [
+    [
+        [60, 36, KICK], [0, 0, 0], [63, 0, HAT], [0, 0, 0],
+        [67, 0, SNARE], [0, 0, 0], [63, 0, HAT], [0, 0, 0],
+        [65, 41, KICK], [0, 0, 0], [63, 0, HAT], [0, 0, 0],
+        [62, 43, SNARE], [0, 0, 0], [0, 0, HAT], [0, 0, HAT],
+    ],
+    [
+        [67, 44, KICK], [0, 0, 0], [68, 0, HAT], [0, 0, 0],
+        [70, 0, SNARE], [0, 0, 0], [68, 0, HAT], [0, 0, 0],
+        [67, 46, KICK], [0, 0, 0], [65, 0, HAT], [0, 0, 0],
+        [63, 43, SNARE], [0, 0, 0], [62, 0, HAT], [0, 0, HAT],
+    ],
+    [
+        [60, 36, KICK], [0, 0, 0], [0, 0, HAT], [0, 0, 0],
+        [63, 43, SNARE], [0, 0, 0], [62, 0, HAT], [0, 0, 0],
+        [60, 36, KICK], [0, 0, 0], [0, 0, HAT], [0, 0, 0],
+        [0, 0, SNARE], [0, 0, 0], [0, 0, KICK], [0, 0, KICK],
+    ],
+];
+
+// Rekkjefølgja mønstra vert spela i før songen byrjar om att
+const ORDER: [usize; 4] = [0, 1, 0, 2];
+
+/// Lydeffektane spelet kan spele
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sound {
+    Footstep,
+    Jump,
+    Landing,
+    Bump,
+    DoorOpen,
+    LevelComplete,
+}
+
+impl Sound {
+    const ALL: [Sound; 6] = [
+        Sound::Footstep,
+        Sound::Jump,
+        Sound::Landing,
+        Sound::Bump,
+        Sound::DoorOpen,
+        Sound::LevelComplete,
+    ];
+
+    /// Tonen som spelar lyden
+    fn tone(&self) -> Tone {
+        match self {
+            Sound::Footstep => Tone::new(180, 120, [0, 0, 2, 3], 20, NOISE, 0),
+            Sound::Jump => Tone::new(300, 600, [0, 0, 6, 4], 40, PULSE1, DUTY_25),
+            Sound::Landing => Tone::new(160, 80, [0, 0, 3, 6], 60, TRIANGLE, 0),
+            Sound::Bump => Tone::new(90, 60, [0, 0, 4, 4], 50, TRIANGLE, 0),
+            Sound::DoorOpen => Tone::new(200, 500, [2, 0, 10, 8], 35, PULSE2, DUTY_12_5),
+            Sound::LevelComplete => Tone::new(400, 900, [0, 4, 40, 20], 50, PULSE1, DUTY_50),
+        }
+    }
+
+    /// Viktigare lydar avbryt mindre viktige på same kanal, men ikkje omvendt
+    fn priority(&self) -> u8 {
+        match self {
+            Sound::Footstep => 1,
+            Sound::Jump | Sound::Landing => 2,
+            Sound::Bump => 3,
+            Sound::DoorOpen => 4,
+            Sound::LevelComplete => 5,
+        }
+    }
+}
+
+/// Argumenta til `tone` i WASM-4
+#[derive(Clone, Copy)]
+pub struct Tone {
+    pub frequency: u32,
+    pub duration: u32,
+    pub volume: u32,
+    pub flags: u32,
+}
+
+impl Tone {
+    /// Ein tone som glir frå `start` til `end` Hz, der `envelope` er lengda på (attack,
+    /// decay, sustain, release) i bilete
+    const fn new(start: u32, end: u32, envelope: [u32; 4], volume: u32, channel: u32, mode: u32) -> Tone {
+        let [attack, decay, sustain, release] = envelope;
+        Tone {
+            frequency: start | end << 16,
+            duration: attack << 24 | decay << 16 | sustain | release << 8,
+            volume,
+            flags: channel | mode << 2,
+        }
+    }
+
+    fn channel(&self) -> usize {
+        (self.flags & 0x03) as usize
+    }
+
+    /// Kor mange bilete tonen varer
+    fn frames(&self) -> u32 {
+        (self.duration >> 24 & 0xFF) + (self.duration >> 16 & 0xFF) + (self.duration & 0xFF) + (self.duration >> 8 & 0xFF)
+    }
+}
+
+/// Kva side av spelaren ein lyd kjem frå
+#[derive(Clone, Copy, PartialEq)]
+pub enum Pan {
+    Centre,
+    Left,
+    Right,
+}
+
+/// Ein lyd som kjem frå ein stad i verda, som utgangen, med volum mellom 0 og 1
+#[derive(Clone, Copy)]
+pub struct Cue {
+    pub volume: f32,
+    pub pan: Pan,
+}
+
+impl Cue {
+    fn tone(&self) -> Tone {
+        let pan = match self.pan {
+            Pan::Centre => 0,
+            Pan::Left => PAN_LEFT,
+            Pan::Right => PAN_RIGHT,
+        };
+        let mut tone = Tone::new(660, 990, [0, 2, 3, 12], (CUE_VOLUME * self.volume) as u32, PULSE1, DUTY_12_5);
+        tone.flags |= pan;
+        tone
+    }
+}
+
+/// Frekvensen til ein MIDI-note i Hz, der 69 er A4 på 440 Hz
+fn note_frequency(note: u8) -> u32 {
+    (440.0 * exp2f((note as f32 - 69.0) / 12.0)) as u32
+}
+
+/// Lydane som er sette i kø i løpet av eitt bilete
+#[derive(Clone, Copy)]
+pub struct Sounds {
+    queued: u8,
+}
+
+impl Sounds {
+    pub const fn new() -> Sounds {
+        Sounds { queued: 0 }
+    }
+
+    pub fn play(&mut self, sound: Sound) {
+        self.queued |= 1 << sound as u8;
+    }
+
+    /// Tek ut alle lydane i køa
+    pub fn take(&mut self) -> impl Iterator<Item = Sound> {
+        let queued = self.queued;
+        self.queued = 0;
+        Sound::ALL.into_iter().filter(move |&sound| queued & 1 << sound as u8 != 0)
+    }
+}
+
+/// Held greie på kva som spelar på kvar av dei fire kanalane, slik at viktige lydar ikkje
+/// vert avbrotne av mindre viktige
+pub struct Mixer {
+    channels: [(u8, u32); 4], // (prioritet, bilete att) for kvar kanal
+}
+
+impl Mixer {
+    pub const fn new() -> Mixer {
+        Mixer { channels: [(0, 0); 4] }
+    }
+
+    /// Eitt bilete har gått
+    pub fn update(&mut self) {
+        for (_, frames) in self.channels.iter_mut() {
+            *frames = frames.saturating_sub(1);
+        }
+    }
+
+    /// Gjev tilbake tonen for stadlyden om kanalen hans er ledig; alle andre lydar kan
+    /// avbryte han
+    pub fn play_cue(&mut self, cue: Cue) -> Option<Tone> {
+        let tone = cue.tone();
+        if self.channels[tone.channel()].1 > 0 || tone.volume == 0 {
+            return None;
+        }
+
+        self.channels[tone.channel()] = (0, tone.frames());
+        Some(tone)
+    }
+
+    /// Seier om ein lydeffekt spelar på kanalen
+    fn is_busy(&self, channel: u32) -> bool {
+        self.channels[channel as usize].1 > 0
+    }
+
+    /// Seier om ein lydeffekt spelar på nokon kanal
+    fn any_busy(&self) -> bool {
+        self.channels.iter().any(|&(_, frames)| frames > 0)
+    }
+
+    /// Gjev tilbake tonen som skal spelast for lyden, om kanalen hans er ledig eller spelar
+    /// noko som er mindre viktig
+    pub fn play(&mut self, sound: Sound) -> Option<Tone> {
+        let tone = sound.tone();
+        let (priority, frames) = self.channels[tone.channel()];
+        if frames > 0 && priority > sound.priority() {
+            return None;
+        }
+
+        self.channels[tone.channel()] = (sound.priority(), tone.frames());
+        Some(tone)
+    }
+}
+
+/// Spelar bakgrunnsmusikken rad for rad, og byrjar om att når songen er ferdig
+pub struct Music {
+    position: usize,
+    row: usize,
+    frame: u32,
+}
+
+impl Music {
+    pub const fn new() -> Music {
+        Music { position: 0, row: 0, frame: 0 }
+    }
+
+    /// Gjev tilbake tonane som skal spelast i dette biletet for (melodi, bass, trommer);
+    /// musikken vert dempa medan lydeffektar spelar, og viker for dei på same kanal
+    pub fn update(&mut self, mixer: &Mixer) -> [Option<Tone>; 3] {
+        let mut tones = [None; 3];
+        if self.frame == 0 {
+            let [melody, bass, drums] = PATTERNS[ORDER[self.position]][self.row];
+            let volume = if mixer.any_busy() { 2 } else { 1 };
+
+            if melody != 0 {
+                tones[0] = Some(Tone::new(note_frequency(melody), 0, [0, 1, 4, 3], 20 / volume, PULSE2, DUTY_25));
+            }
+            if bass != 0 {
+                tones[1] = Some(Tone::new(note_frequency(bass), 0, [0, 0, 12, 4], 30 / volume, TRIANGLE, 0));
+            }
+            tones[2] = match drums {
+                KICK => Some(Tone::new(140, 50, [0, 0, 2, 4], 30 / volume, NOISE, 0)),
+                SNARE => Some(Tone::new(500, 300, [0, 0, 2, 5], 20 / volume, NOISE, 0)),
+                HAT => Some(Tone::new(900, 900, [0, 0, 1, 1], 10 / volume, NOISE, 0)),
+                _ => None,
+            };
+
+            // neste rad, og neste mønster når dette er ferdig
+            self.row += 1;
+            if self.row == PATTERN_LENGTH {
+                self.row = 0;
+                self.position = (self.position + 1) % ORDER.len();
+            }
+        }
+        self.frame = (self.frame + 1) % FRAMES_PER_ROW;
+
+        tones.map(|tone| tone.filter(|tone| !mixer.is_busy(tone.flags & 0x03)))
+    }
+}
@@ -1,27 +1,229 @@
-use libm::{ceilf, cosf, fabsf, floorf, sinf, sqrtf, tanf};
+use core::cell::Cell;
+use libm::{atan2f, ceilf, cosf, fabsf, floorf, sinf, sqrtf, tanf};
 use core::f32::consts::{FRAC_PI_2, PI};
 use crate::constants::{FRAME_WIDTH, SCREEN_SIZE};
-use crate::map::{Orientation, read_map, Terrain};
+use crate::map::{Edge, Key, Map, Orientation, Portal, Terrain, MAP_HEIGHT, MAP_WIDTH, MAX_WALL_HEIGHT};
+use crate::sound::{Cue, Pan, Sound, Sounds};
+use crate::text::{Language, Text};
 
 const STEP_SIZE: f32 = 0.045;
 const GRAVITATIONAL_ACCELERATION: f32 = 6.0;
 const INITIAL_JUMP_SPEED: f32 = 3.0;
+pub const TRANSITION_TIME: f32 = 0.4; // Sekund skjermen flimrar etter ein teleport
+pub const HUD_HEIGHT: u32 = 24; // Høgda til statuslinja nedst på skjermen
+pub const VIEW_HEIGHT: u32 = SCREEN_SIZE - HUD_HEIGHT; // Høgda til førstepersonsvisinga over statuslinja
+pub const MAX_HEALTH: u8 = 100;
+const BUMP_DAMAGE: u8 = 10; // Kor mykje helse spelaren mistar kvar gong han går inn i ein vegg
+const BOB_HEIGHT: f32 = 0.025; // Kor mykje augo går opp og ned når spelaren går, i veggeiningar
+const BOB_LENGTH: f32 = 0.8; // Kor langt spelaren går for kvart steg, i ruter
+const LANDING_DIP: f32 = 0.08; // Kor langt augo søkk når spelaren landar etter eit hopp
+const LANDING_TIME: f32 = 0.25; // Sekund det tek før augo er tilbake etter ei landing
+const CROUCH_DEPTH: f32 = 0.25; // Kor mykje lågare augo er når spelaren kryp, i veggeiningar
+const CROUCH_SPEED: f32 = 6.0; // Kor fort spelaren går ned i og reiser seg frå krypestilling
+const HEAD_ROOM: f32 = 0.1; // Kor langt over augo toppen av hovudet er
 
 const FOV: f32 = PI / 2.7; // Spelarens synsfelt
 const HALF_FOV: f32 = FOV * 0.5; // Halve spelarens synsfelt
 const ANGLE_STEP: f32 = FOV / (SCREEN_SIZE as f32); // Vinkelen mellom kvar stråle
 const WALL_HEIGHT: f32 = 100.0; // Eit magisk tal?
+const MAX_HITS: usize = 4; // Kor mange veggar bak kvarandre som vert teikna i kvar kolonne
+const MAX_FLY_HITS: usize = MAP_WIDTH + MAP_HEIGHT; // Kor mange veggar ei stråle kan treffe når kameraet ser ned på heile kartet
+const MAX_INSPECTED: usize = 24; // Kor mange krysningar langs kvar gitterretning inspektøren viser
+const WALL_MARGIN: f32 = 0.2; // Kor nær ein tynn eller skrå vegg spelaren kan gå
+const MAX_BOUNCES: usize = 2; // Kor mange speglar og portalar ei stråle kan gå gjennom
+const PLAYER_RADIUS: f32 = 0.2; // Halve breidda til spelaren slik han ser ut i ein spegel
+const CUE_INTERVAL: u32 = 90; // Kor mange bilete det går mellom kvar gong utgangen lagar lyd
+const CUE_RANGE: f32 = 10.0; // Kor langt unna utgangen kan høyrast, i ruter
+const CUE_CENTRE: f32 = PI / 8.0; // Lydar innanfor denne vinkelen framfor eller bak spelaren kjem frå midten
+const CUE_OCCLUSION: f32 = 0.35; // Kor mykje av lyden som kjem gjennom ein vegg
+const FLY_SPEED: f32 = 1.5; // Kor fort det frie kameraet stig og søkk, i veggeiningar per sekund
+const MIN_FLY_Z: f32 = -0.8; // Kor langt ned mot golvet det frie kameraet kan søkke
+const MAX_FLY_Z: f32 = 6.0; // Kor høgt over veggane det frie kameraet kan stige
+const SLOW_MOTION: u32 = 4; // Kor mange bilete det går for kvart steg i sakte film
 
 pub enum View {
     Map,
     MapWithRays,
+    RayInspector,
     FishEyeMonochrome,
     FirstPersonMonochrome,
     FirstPerson,
+    Settings,
+}
+
+/// Innstillingane spelaren kan slå av og på i innstillingsmenyen
+#[derive(Clone, Copy, PartialEq)]
+pub enum Setting {
+    Music,
+    HeadBob,
+    Sky,
+    Minimap,
+    Language,
+    Debug,
+    Noclip,
+    Speed,
+    TwoPlayers,
+    MapZoom,
+}
+
+impl Setting {
+    pub const ALL: [Setting; 10] = [
+        Setting::Music,
+        Setting::HeadBob,
+        Setting::Sky,
+        Setting::Minimap,
+        Setting::Language,
+        Setting::Debug,
+        Setting::Noclip,
+        Setting::Speed,
+        Setting::TwoPlayers,
+        Setting::MapZoom,
+    ];
+
+    /// Namnet på innstillinga i menyen
+    pub fn label(&self) -> Text {
+        match self {
+            Setting::Music => Text::Music,
+            Setting::HeadBob => Text::HeadBob,
+            Setting::Sky => Text::Ceiling,
+            Setting::Minimap => Text::Minimap,
+            Setting::Language => Text::Language,
+            Setting::Debug => Text::Debug,
+            Setting::Noclip => Text::Noclip,
+            Setting::Speed => Text::Speed,
+            Setting::TwoPlayers => Text::TwoPlayers,
+            Setting::MapZoom => Text::MapZoom,
+        }
+    }
+}
+
+/// Det som vert teikna over horisonten der ingen vegg er i vegen
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sky {
+    Panorama,
+    Ceiling,
+}
+
+/// Kor fort spelet går, slik at kollisjonar og hopp kan følgjast bilete for bilete
+#[derive(Clone, Copy, PartialEq)]
+pub enum Speed {
+    Normal,
+    Slow,
+    Paused,
+}
+
+/// Retninga og skaleringa golvet og taket i éi kolonne vert rekna ut frå
+pub struct Surface {
+    cos: f32,
+    sin: f32,
+    stretch: f32,
+    horizon: f32,
+    eye: f32,
+}
+
+/// Kor nært kartvisingane syner labyrinten
+#[derive(Clone, Copy, PartialEq)]
+pub enum Zoom {
+    Whole,
+    Medium,
+    Close,
+}
+
+impl Zoom {
+    /// Storleiken på ei rute i pikslar
+    pub fn tile_size(&self) -> i32 {
+        match self {
+            Zoom::Whole => 7,
+            Zoom::Medium => 11,
+            Zoom::Close => 16,
+        }
+    }
+}
+
+/// Kvar feilsøkingsinformasjonen vert vist
+#[derive(Clone, Copy, PartialEq)]
+pub enum Debug {
+    Off,
+    Overlay,
+    Trace,
+}
+
+/// Kor mykje arbeid strålane har kravd sidan teljarane sist vart nullstilte, slik at
+/// raycast kan telje sjølv om han berre låner tilstanden
+pub struct Stats {
+    pub rays: Cell<u32>,
+    pub steps: Cell<u32>,
+}
+
+impl Stats {
+    pub const fn new() -> Stats {
+        Stats { rays: Cell::new(0), steps: Cell::new(0) }
+    }
+
+    pub fn reset(&self) {
+        self.rays.set(0);
+        self.steps.set(0);
+    }
+}
+
+/// Knappane ein spelar held inne i dette biletet; for det frie kameraet stig det med
+/// `jump` og søkk med `crouch`
+#[derive(Clone, Copy)]
+pub struct Controls {
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+    pub jump: bool,
+    pub crouch: bool,
+}
+
+/// Den delen av skjermen førstepersonsvisinga til ein spelar fyller
+#[derive(Clone, Copy)]
+pub struct Viewport {
+    pub top: i32,
+    pub height: u32,
+}
+
+impl Viewport {
+    pub fn bottom(&self) -> i32 {
+        self.top + self.height as i32
+    }
+}
+
+/// Om minikartet vert teikna oppå førstepersonsvisinga, og om det då snur seg med spelaren
+#[derive(Clone, Copy, PartialEq)]
+pub enum Minimap {
+    Off,
+    Fixed,
+    Rotating,
+}
+
+/// Nøklane spelaren har plukka opp
+pub struct Inventory {
+    keys: u8,
+}
+
+impl Inventory {
+    pub const fn new() -> Inventory {
+        Inventory { keys: 0 }
+    }
+
+    pub fn has_key(&self, key: Key) -> bool {
+        self.keys & (1 << key as u8) != 0
+    }
+
+    pub fn add_key(&mut self, key: Key) {
+        self.keys |= 1 << key as u8;
+    }
 }
 
 pub struct State {
     pub view: View,
+    pub minimap: Minimap,
+    pub map_zoom: Zoom,
+    pub inventory: Inventory,
+    pub transition: f32,
     pub player_x: f32,
     pub player_y: f32,
     pub player_z: f32,
@@ -30,20 +232,88 @@ pub struct State {
     pub player_angle: f32,
     pub player_angular_velocity: f32,
     pub previous_gamepad: u8,
+    pub ticks: u32,
+    pub flicker: f32, // Kor sterkt faklene lyser i dette biletet
+    pub head_bob: bool,
+    pub walked: f32,
+    pub landing: f32,
+    pub shake_time: f32,
+    pub shake_strength: f32,
+    pub crouch: f32,
+    pub crouching: bool, // Om spelaren har slått på kryping
+    pub sky: Sky,
+    pub sounds: Sounds,
+    pub blocked: bool,
+    pub completed: bool,
+    pub music: bool,
+    pub setting: usize,
+    pub health: u8,
+    pub start: (f32, f32, f32), // Staden (x, y) og vinkelen spelaren byrjar brettet med
+    pub language: Language,
+    pub debug: Debug,
+    pub stats: Stats,
+    pub noclip: bool,
+    pub noclip_origin: (f32, f32),
+    pub speed: Speed,
+    pub frames: u32,
+    pub inspected_column: usize,
+    pub viewport: Viewport,
+    pub two_players: bool,
+    pub rival: Option<(f32, f32, f32)>, // Posisjonen (x, y, z) til den andre spelaren i tospelarmodus
 }
 
 fn distance(a: f32, b: f32) -> f32 {
     sqrtf((a * a) + (b * b))
 }
 
+/// Gjev tilbake avstandane der ei stråle frå origin går inn i og ut av området [min, max]
+fn slab(origin: f32, direction: f32, min: f32, max: f32) -> (f32, f32) {
+    if direction == 0.0 {
+        return if origin >= min && origin <= max {
+            (f32::NEG_INFINITY, f32::INFINITY)
+        } else {
+            (f32::INFINITY, f32::NEG_INFINITY)
+        };
+    }
+
+    let a = (min - origin) / direction;
+    let b = (max - origin) / direction;
+    (a.min(b), a.max(b))
+}
+
+/// Kor sterkt faklene lyser i biletet `ticks`, slik at lyset frå dei flakkar litt
+fn flicker(ticks: u32) -> f32 {
+    let time = ticks as f32;
+    0.8 + 0.1 * sinf(time * 0.37) + 0.1 * sinf(time * 0.91)
+}
+
 impl State {
     /// Flytter spelaren
-    pub fn update(&mut self, up: bool, down: bool, left: bool, right: bool, jump: bool) {
+    pub fn update(&mut self, map: &mut Map, controls: Controls) {
+        let Controls { up, down, left, right, jump, crouch } = controls;
+
         // lagre noverandre posisjon i det høvet vi treng han seinare
         let previous_position = (self.player_x, self.player_y);
 
+        self.ticks = self.ticks.wrapping_add(1);
+        self.flicker = flicker(self.ticks);
+
+        if self.noclip {
+            self.fly(up, down, left, right, jump, crouch);
+            return;
+        }
+
+        // spelaren held fram å krype så lenge taket er for lågt til å stå oppreist
+        let standing_head = 0.5 + self.player_z * 0.5 + HEAD_ROOM;
+        let ceiling = map.read_map(self.player_x, self.player_y).ceiling();
+        let crouch_target: f32 = if crouch || standing_head > ceiling { 1.0 } else { 0.0 };
+        let crouch_step = CROUCH_SPEED * FRAME_WIDTH;
+        self.crouch = crouch_target.clamp(self.crouch - crouch_step, self.crouch + crouch_step);
+
         if self.player_z == 0.0 && !jump {
-            self.player_velocity = STEP_SIZE * up as i32 as f32 - STEP_SIZE * down as i32 as f32;
+            // spelaren går saktare når han kryp
+            let step_size = STEP_SIZE * (1.0 - 0.5 * self.crouch);
+            self.player_velocity = step_size * up as i32 as f32 - step_size * down as i32 as f32;
             self.player_angular_velocity = STEP_SIZE * left as i32 as f32 - STEP_SIZE * right as i32 as f32;
         }
 
@@ -51,26 +321,114 @@ impl State {
         self.player_y += -sinf(self.player_angle) * self.player_velocity;
         self.player_angle += self.player_angular_velocity;
 
-        match read_map(self.player_x, self.player_y) {
-            Terrain::Open => {},
-            Terrain::Wall => {
-                if read_map(self.player_x, previous_position.1) == Terrain::Open {
+        // blocked seier om spelaren gjekk inn i noko i dette biletet
+        let mut blocked = false;
+
+        // tynne og skrå veggar stoppar spelaren berre ved sjølve veggstykket
+        if self.crosses_segment(map, previous_position, (self.player_x, self.player_y)) {
+            if !self.crosses_segment(map, previous_position, (self.player_x, previous_position.1)) {
+                self.player_y = previous_position.1;
+            } else if !self.crosses_segment(map, previous_position, (previous_position.0, self.player_y)) {
+                self.player_x = previous_position.0;
+            } else {
+                self.player_x = previous_position.0;
+                self.player_y = previous_position.1;
+            }
+            blocked = true;
+        }
+
+        // går spelaren inn i ein portal, kjem han ut av den andre portalen
+        if let Some(portal) = self.portal_entered(map, previous_position) {
+            (self.player_x, self.player_y, self.player_angle) =
+                portal.transform(self.player_x, self.player_y, self.player_angle);
+        }
+
+        match map.read_map(self.player_x, self.player_y) {
+            Terrain::Open | Terrain::Lamp | Terrain::ThinWall(_) | Terrain::DiagonalWall(_) => {},
+            Terrain::LowPassage if self.can_enter(Terrain::LowPassage) => {},
+            Terrain::Key(key) => {
+                self.inventory.add_key(key);
+                map.clear(self.player_x, self.player_y);
+            },
+            Terrain::Teleporter => {
+                // berre når spelaren kjem inn på ruta, elles vert han sendt fram og tilbake
+                let entered = floorf(previous_position.0) != floorf(self.player_x)
+                    || floorf(previous_position.1) != floorf(self.player_y);
+                if let Some(teleporter) = map.teleporter(self.player_x, self.player_y).filter(|_| entered) {
+                    self.player_x = teleporter.to_x as f32 + 0.5;
+                    self.player_y = teleporter.to_y as f32 + 0.5;
+                    if let Some(angle) = teleporter.angle {
+                        self.player_angle = angle;
+                    }
+                    self.transition = TRANSITION_TIME;
+                }
+            },
+            Terrain::LockedDoor(key) if self.inventory.has_key(key) => {
+                map.clear(self.player_x, self.player_y);
+                self.sounds.play(Sound::DoorOpen);
+            },
+            // døropninga er utgangen frå labyrinten
+            Terrain::Doorway => {
+                self.player_x = previous_position.0;
+                self.player_y = previous_position.1;
+                if !self.completed {
+                    self.completed = true;
+                    self.sounds.play(Sound::LevelComplete);
+                }
+            },
+            terrain => {
+                if terrain == Terrain::PushWall && map.push_wall.is_none() {
+                    let (dx, dy) = self.facing();
+                    map.push(self.player_x, self.player_y, dx, dy);
+                    if map.push_wall.is_some() {
+                        self.shake(2.0, 0.5);
+                        self.sounds.play(Sound::DoorOpen);
+                    }
+                }
+
+                blocked = true;
+
+                if self.can_enter(map.read_map(self.player_x, previous_position.1)) {
                     self.player_y = previous_position.1;
-                } else if read_map(previous_position.0, self.player_y) == Terrain::Open {
+                } else if self.can_enter(map.read_map(previous_position.0, self.player_y)) {
                     self.player_x = previous_position.0;
                 } else {
                     self.player_x = previous_position.0;
                     self.player_y = previous_position.1;
                 }
             },
-            Terrain::Doorway => {
-                self.player_x = previous_position.0;
-                self.player_y = previous_position.1;
-            },
+        }
+
+        self.transition = (self.transition - FRAME_WIDTH).max(0.0);
+        self.landing = (self.landing - FRAME_WIDTH).max(0.0);
+        self.shake_time = (self.shake_time - FRAME_WIDTH).max(0.0);
+
+        // det dunkar berre når spelaren går inn i veggen, ikkje så lenge han står inntil
+        if blocked && !self.blocked {
+            self.sounds.play(Sound::Bump);
+            self.health = self.health.saturating_sub(BUMP_DAMAGE);
+        }
+        self.blocked = blocked;
+
+        // utan helse att må spelaren tilbake til starten, men held på nøklane
+        if self.health == 0 {
+            (self.player_x, self.player_y, self.player_angle) = self.start;
+            self.health = MAX_HEALTH;
+            self.transition = TRANSITION_TIME;
+        }
+
+        // eitt fotsteg for kvar steglengd spelaren går
+        if self.player_z == 0.0 {
+            let steps = floorf(self.walked / BOB_LENGTH);
+            self.walked += fabsf(self.player_velocity);
+            if floorf(self.walked / BOB_LENGTH) != steps {
+                self.sounds.play(Sound::Footstep);
+            }
         }
 
         if jump && self.player_z == 0.0 {
             self.player_z_velocity = INITIAL_JUMP_SPEED;
+            self.sounds.play(Sound::Jump);
         }
 
         if self.player_z > 0.0 {
@@ -78,24 +436,285 @@ impl State {
             self.player_angular_velocity *= 0.975;
         }
 
+        let airborne = self.player_z > 0.0;
         self.player_z += self.player_z_velocity * FRAME_WIDTH;
         self.player_z_velocity -= GRAVITATIONAL_ACCELERATION * FRAME_WIDTH;
+
+        // hovudet stoggar mot taket i ruta spelaren er i
+        let ceiling = map.read_map(self.player_x, self.player_y).ceiling();
+        if self.head_height() > ceiling {
+            self.player_z = ((ceiling - self.head_height() + self.player_z * 0.5) / 0.5).max(0.0);
+            self.player_z_velocity = self.player_z_velocity.min(0.0);
+        }
         if self.player_z <= 0.0 {
             self.player_z = 0.0;
             self.player_z_velocity = 0.0;
+            if airborne {
+                self.landing = LANDING_TIME;
+                self.sounds.play(Sound::Landing);
+            }
+        }
+    }
+
+    /// Kor høgt over golvet toppen av hovudet til spelaren er, utan at det gyngar
+    fn head_height(&self) -> f32 {
+        0.5 - self.crouch * CROUCH_DEPTH + self.player_z * 0.5 + HEAD_ROOM
+    }
+
+    /// Seier om spelaren kan gå inn i ei rute med denne terrengtypen slik han står no
+    fn can_enter(&self, terrain: Terrain) -> bool {
+        self.head_height() <= terrain.ceiling()
+    }
+
+    /// Flyttar markøren i innstillingsmenyen og slår av eller på innstillinga han står på
+    pub fn update_settings(&mut self, map: &Map, up: bool, down: bool, toggle: bool) {
+        let count = Setting::ALL.len();
+        if up {
+            self.setting = (self.setting + count - 1) % count;
+        }
+        if down {
+            self.setting = (self.setting + 1) % count;
+        }
+        if toggle {
+            match Setting::ALL[self.setting] {
+                Setting::Music => self.music = !self.music,
+                Setting::HeadBob => self.head_bob = !self.head_bob,
+                Setting::Sky => {
+                    self.sky = match self.sky {
+                        Sky::Panorama => Sky::Ceiling,
+                        Sky::Ceiling => Sky::Panorama,
+                    }
+                }
+                Setting::Minimap => {
+                    self.minimap = match self.minimap {
+                        Minimap::Off => Minimap::Fixed,
+                        Minimap::Fixed => Minimap::Rotating,
+                        Minimap::Rotating => Minimap::Off,
+                    }
+                }
+                Setting::Language => {
+                    self.language = match self.language {
+                        Language::Nynorsk => Language::English,
+                        Language::English => Language::Nynorsk,
+                    }
+                }
+                Setting::Noclip => self.toggle_noclip(map),
+                Setting::TwoPlayers => self.two_players = !self.two_players,
+                Setting::Speed => {
+                    self.speed = match self.speed {
+                        Speed::Normal => Speed::Slow,
+                        Speed::Slow => Speed::Paused,
+                        Speed::Paused => Speed::Normal,
+                    }
+                }
+                Setting::Debug => {
+                    self.debug = match self.debug {
+                        Debug::Off => Debug::Overlay,
+                        Debug::Overlay => Debug::Trace,
+                        Debug::Trace => Debug::Off,
+                    }
+                }
+                Setting::MapZoom => {
+                    self.map_zoom = match self.map_zoom {
+                        Zoom::Whole => Zoom::Medium,
+                        Zoom::Medium => Zoom::Close,
+                        Zoom::Close => Zoom::Whole,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Seier om spelet skal gå eitt steg fram i dette biletet: alltid i vanleg fart, kvart
+    /// fjerde bilete i sakte film, og berre når `step` er trykt når spelet står stille
+    pub fn advance(&mut self, step: bool) -> bool {
+        self.frames = self.frames.wrapping_add(1);
+        match self.speed {
+            Speed::Normal => true,
+            Speed::Slow => self.frames % SLOW_MOTION == 0,
+            Speed::Paused => step,
+        }
+    }
+
+    /// Set spelaren tilbake til starten med full helse, utan nøklar og med klokka nullstilt,
+    /// ståande på golvet utan det frie kameraet
+    pub fn restart(&mut self) {
+        (self.player_x, self.player_y, self.player_angle) = self.start;
+        self.player_z = 0.0;
+        self.player_velocity = 0.0;
+        self.player_z_velocity = 0.0;
+        self.player_angular_velocity = 0.0;
+        self.noclip = false;
+        self.crouch = 0.0;
+        self.crouching = false;
+        self.walked = 0.0;
+        self.landing = 0.0;
+        self.shake_time = 0.0;
+        self.blocked = false;
+        self.health = MAX_HEALTH;
+        self.inventory = Inventory::new();
+        self.completed = false;
+        self.ticks = 0;
+        self.transition = 0.0;
+    }
+
+    /// Slår det frie kameraet av eller på; står kameraet ein stad spelaren ikkje kan vere når
+    /// det vert slått av, kjem spelaren tilbake dit kameraet vart slått på
+    fn toggle_noclip(&mut self, map: &Map) {
+        self.noclip = !self.noclip;
+        if self.noclip {
+            self.noclip_origin = (self.player_x, self.player_y);
+            self.player_velocity = 0.0;
+            self.player_z_velocity = 0.0;
+            return;
+        }
+
+        let terrain = map.read_map(self.player_x, self.player_y);
+        if !map.contains(self.player_x, self.player_y) || !terrain.is_walkable() {
+            (self.player_x, self.player_y) = self.noclip_origin;
         }
+        self.player_z = self.player_z.max(0.0);
     }
 
-    /// Gjev tilbake næraste vegg som ei stråle skjer langsetter ei horisontal linje
-    fn horizontal_intersection(&self, angle: f32) -> Ray {
+    /// Flyttar det frie kameraet gjennom veggar og opp og ned, utan tyngdekraft
+    fn fly(&mut self, forward: bool, back: bool, left: bool, right: bool, rise: bool, sink: bool) {
+        self.player_velocity = STEP_SIZE * forward as i32 as f32 - STEP_SIZE * back as i32 as f32;
+        self.player_angular_velocity = STEP_SIZE * left as i32 as f32 - STEP_SIZE * right as i32 as f32;
+
+        self.player_x += cosf(self.player_angle) * self.player_velocity;
+        self.player_y += -sinf(self.player_angle) * self.player_velocity;
+        self.player_angle += self.player_angular_velocity;
+
+        let climb = FLY_SPEED * FRAME_WIDTH * (rise as i32 - sink as i32) as f32;
+        self.player_z = (self.player_z + climb).clamp(MIN_FLY_Z, MAX_FLY_Z);
+        self.crouch = 0.0;
+
+        self.transition = (self.transition - FRAME_WIDTH).max(0.0);
+        self.landing = (self.landing - FRAME_WIDTH).max(0.0);
+        self.shake_time = (self.shake_time - FRAME_WIDTH).max(0.0);
+    }
+
+    /// Teksten som syner kva innstillinga står på no
+    pub fn setting_value(&self, setting: Setting) -> Text {
+        let enabled = match setting {
+            Setting::Music => self.music,
+            Setting::HeadBob => self.head_bob,
+            Setting::Sky => self.sky == Sky::Ceiling,
+            Setting::Minimap => {
+                return match self.minimap {
+                    Minimap::Off => Text::Off,
+                    Minimap::Fixed => Text::MinimapFixed,
+                    Minimap::Rotating => Text::MinimapRotating,
+                }
+            }
+            Setting::Noclip => self.noclip,
+            Setting::TwoPlayers => self.two_players,
+            Setting::Language => return Text::LanguageName,
+            Setting::Speed => {
+                return match self.speed {
+                    Speed::Normal => Text::SpeedNormal,
+                    Speed::Slow => Text::SpeedSlow,
+                    Speed::Paused => Text::SpeedPaused,
+                }
+            }
+            Setting::Debug => {
+                return match self.debug {
+                    Debug::Off => Text::Off,
+                    Debug::Overlay => Text::DebugOverlay,
+                    Debug::Trace => Text::DebugTrace,
+                }
+            }
+            Setting::MapZoom => {
+                return match self.map_zoom {
+                    Zoom::Whole => Text::ZoomWhole,
+                    Zoom::Medium => Text::ZoomMedium,
+                    Zoom::Close => Text::ZoomClose,
+                }
+            }
+        };
+        if enabled { Text::On } else { Text::Off }
+    }
+
+    /// Ristar skjermen med `strength` pikslar i `duration` sekund, til dømes når noko tungt
+    /// set seg i rørsle
+    pub fn shake(&mut self, strength: f32, duration: f32) {
+        self.shake_strength = strength;
+        self.shake_time = duration;
+    }
+
+    /// Portalen spelaren har gått inn i sidan `previous_position`, om nokon
+    fn portal_entered(&self, map: &Map, previous_position: (f32, f32)) -> Option<&'static Portal> {
+        let (x, y) = (floorf(self.player_x), floorf(self.player_y));
+        let (previous_x, previous_y) = (floorf(previous_position.0), floorf(previous_position.1));
+        let side = if previous_x < x {
+            Edge::West
+        } else if previous_x > x {
+            Edge::East
+        } else if previous_y < y {
+            Edge::North
+        } else if previous_y > y {
+            Edge::South
+        } else {
+            return None;
+        };
+        map.portal(self.player_x, self.player_y, side)
+    }
+
+    /// Gjev tilbake den av dei fire himmelretningane spelaren ser mest mot
+    fn facing(&self) -> (f32, f32) {
+        let x = cosf(self.player_angle);
+        let y = -sinf(self.player_angle);
+        if fabsf(x) > fabsf(y) {
+            (if x > 0.0 { 1.0 } else { -1.0 }, 0.0)
+        } else {
+            (0.0, if y > 0.0 { 1.0 } else { -1.0 })
+        }
+    }
+
+    /// Gjev tilbake ein skyvevegg i rørsle dersom strålen treff han
+    fn push_wall_intersection(&self, map: &Map, beam: &Beam) -> Option<Ray> {
+        let push_wall = map.push_wall?;
+        let (left, top, right, bottom) = push_wall.bounds();
+
+        // near og far er avstandane der strålen går inn i og ut av veggen langs kvar akse
+        let (near_x, far_x) = slab(beam.x, cosf(beam.angle), left, right);
+        let (near_y, far_y) = slab(beam.y, -sinf(beam.angle), top, bottom);
+        let near = near_x.max(near_y);
+        let far = far_x.min(far_y);
+        if near > far || near <= 0.0 {
+            return None;
+        }
+
+        // offset er kor langt inn på sida av veggen strålen treff
+        let (orientation, offset) = if near_x > near_y {
+            (Orientation::Vertical, beam.y - sinf(beam.angle) * near - top)
+        } else {
+            (Orientation::Horizontal, beam.x + cosf(beam.angle) * near - left)
+        };
+
+        Some(Ray {
+            angle_diff: beam.angle_diff,
+            distance: beam.travelled + near,
+            exit_distance: beam.travelled + far,
+            offset,
+            terrain: Terrain::PushWall,
+            orientation,
+            light: 1.0,
+        })
+    }
+
+    /// Gjev tilbake alle rutene ei stråle går inn i langsetter horisontale linjer
+    fn horizontal_intersections<'a>(&self, map: &'a Map, beam: &Beam) -> GridWalk<'a> {
+        let angle = beam.angle;
+
         // Seier om vinkelen peikar nordover (i det heile)
         let up = fabsf(floorf(angle / PI) % 2.0) != 0.0;
 
         // first_x og first_y er dei første skjeringspunkt mellom stråle og gitter
         let first_y = if up {
-            ceilf(self.player_y) - self.player_y
+            ceilf(beam.y) - beam.y
         } else {
-            floorf(self.player_y) - self.player_y
+            floorf(beam.y) - beam.y
         };
         let first_x = -first_y / tanf(angle);
 
@@ -103,64 +722,33 @@ impl State {
         let dy = if up { 1.0 } else { -1.0 };
         let dx = -dy / tanf(angle);
 
-        // next_x og next_y held styr på kor langt strålen er frå spelaren
-        let mut next_x = first_x;
-        let mut next_y = first_y;
-        let mut terrain: Terrain;
-
-        // Lykkje kor strålen forlengast til han når ein vegg
-        for _ in 0..256 {
-            // current_x og current_y er strålens noverande posisjon
-            let current_x = next_x + self.player_x;
-            let current_y = if up {
-                next_y + self.player_y
-            } else {
-                next_y + self.player_y - 1.0
-            };
-
-            // Lykkja stoggar når strålen kjem til ein vegg
-            terrain = read_map(current_x, current_y);
-            if terrain == Terrain::Wall {
-                return Ray {
-                    angle_diff: angle - self.player_angle,
-                    distance: distance(next_x, next_y),
-                    terrain: Terrain::Wall,
-                    orientation: Orientation::Horizontal,
-                }
-            }
-            if terrain != Terrain::Open {
-                return Ray {
-                    angle_diff: angle - self.player_angle,
-                    distance: 100.0,
-                    terrain: Terrain::Wall,
-                    orientation: Orientation::Horizontal,
-                }
-            }
-
-            // forleng strålen så lenge me ikkje har nådd ein vegg
-            next_x += dx;
-            next_y += dy;
-        }
-
-        // gje tilbake avstanden fra (next_x, next_y) til spelarens posisjon
-        Ray {
-            angle_diff: angle - self.player_angle,
-            distance: distance(next_x, next_y),
-            terrain: Terrain::Wall,
+        GridWalk {
+            map,
+            origin_x: beam.x,
+            origin_y: if up { beam.y } else { beam.y - 1.0 },
+            next_x: first_x,
+            next_y: first_y,
+            dx,
+            dy,
+            angle_diff: beam.angle_diff,
+            travelled: beam.travelled,
             orientation: Orientation::Horizontal,
+            steps: 256,
         }
     }
 
-    /// Gjev tilbake næraste vegg som ei stråle skjer langsetter ei vertikal linje
-    fn vertical_intersection(&self, angle: f32) -> Ray {
+    /// Gjev tilbake alle rutene ei stråle går inn i langsetter vertikale linjer
+    fn vertical_intersections<'a>(&self, map: &'a Map, beam: &Beam) -> GridWalk<'a> {
+        let angle = beam.angle;
+
         // Seier om vinkelen peikar nordover (i det heile)
         let right = fabsf(floorf((angle - FRAC_PI_2) / PI) % 2.0) != 0.0;
 
         // first_x og first_y er dei første skjeringspunkt mellom stråle og gitter
         let first_x = if right {
-            ceilf(self.player_x) - self.player_x
+            ceilf(beam.x) - beam.x
         } else {
-            floorf(self.player_x) - self.player_x
+            floorf(beam.x) - beam.x
         };
         let first_y = -tanf(angle) * first_x;
 
@@ -168,86 +756,673 @@ impl State {
         let dx = if right { 1.0 } else { -1.0 };
         let dy = dx * -tanf(angle);
 
-        // next_x og next_y held styr på kor langt strålen er frå spelaren
-        let mut next_x = first_x;
-        let mut next_y = first_y;
-        let mut terrain: Terrain;
+        GridWalk {
+            map,
+            origin_x: if right { beam.x } else { beam.x - 1.0 },
+            origin_y: beam.y,
+            next_x: first_x,
+            next_y: first_y,
+            dx,
+            dy,
+            angle_diff: beam.angle_diff,
+            travelled: beam.travelled,
+            orientation: Orientation::Vertical,
+            steps: 256,
+        }
+    }
 
-        // Lykkje kor strålen forlengast til han når ein vegg
-        for _ in 0..256 {
-            // current_x og current_y er strålens noverande posisjon
-            let current_x = if right {
-                next_x + self.player_x
-            } else {
-                next_x + self.player_x - 1.0
-            };
-            let current_y = next_y + self.player_y;
-
-            // Lykkja stoggar når strålen kjem til ein vegg
-            terrain = read_map(current_x, current_y);
-            if terrain == Terrain::Wall {
-                return Ray {
-                    angle_diff: angle - self.player_angle,
-                    distance: distance(next_x, next_y),
-                    terrain: Terrain::Wall,
-                    orientation: Orientation::Vertical,
+    /// Gjev tilbake veggane i kvar kolonne på skjermen, frå venstre mot høgre
+    pub fn get_rays<'a>(&'a self, map: &'a Map) -> impl Iterator<Item = Column> + 'a {
+        let initial_angle = self.player_angle + HALF_FOV;
+
+        (0..SCREEN_SIZE as usize).map(move |idx| self.raycast(map, initial_angle - idx as f32 * ANGLE_STEP))
+    }
+
+    /// Augehøgda til spelaren, målt i veggeiningar frå golvet
+    pub fn eye_height(&self) -> f32 {
+        // augo går opp og ned i takt med stega så lenge spelaren går
+        let bob = if self.head_bob {
+            BOB_HEIGHT * sinf(self.walked * PI / BOB_LENGTH) * fabsf(self.player_velocity) / STEP_SIZE
+        } else {
+            0.0
+        };
+
+        // etter ei landing søkk augo litt og kjem så sakte opp att
+        let dip = LANDING_DIP * sinf(self.landing / LANDING_TIME * PI);
+
+        0.5 - self.crouch * CROUCH_DEPTH + self.player_z * 0.5 + bob - dip
+    }
+
+    /// Skjermrada horisonten ligg på, som flyttar seg opp og ned når skjermen ristar
+    pub fn horizon(&self) -> f32 {
+        let fade = (self.shake_time * 4.0).min(1.0);
+        self.viewport.top as f32 + self.viewport.height as f32 / 2.0 + self.shake_strength * fade * sinf(self.ticks as f32 * 2.3)
+    }
+
+    /// Gjev tilbake ei stråle frå spelaren mot eit punkt, slik at ein gjenstand der
+    /// kan teiknast med same storleik som ein vegg i same avstand
+    pub fn ray_towards(&self, map: &Map, x: f32, y: f32, terrain: Terrain) -> Ray {
+        let dx = x - self.player_x;
+        let dy = y - self.player_y;
+
+        // vinkelskilnaden vert halden innanfor (-PI, PI]
+        let mut angle_diff = (atan2f(-dy, dx) - self.player_angle) % (2.0 * PI);
+        if angle_diff > PI {
+            angle_diff -= 2.0 * PI;
+        } else if angle_diff <= -PI {
+            angle_diff += 2.0 * PI;
+        }
+
+        Ray {
+            angle_diff,
+            distance: distance(dx, dy),
+            exit_distance: distance(dx, dy),
+            offset: 0.0,
+            terrain,
+            orientation: Orientation::Vertical,
+            light: map.light(x, y, self.flicker),
+        }
+    }
+
+    /// Lyden frå den næraste utgangen, om det er tid for han i dette biletet og han er nær
+    /// nok til å høyrast
+    pub fn exit_cue(&self, map: &Map) -> Option<Cue> {
+        if self.completed || self.ticks % CUE_INTERVAL != 0 {
+            return None;
+        }
+
+        let exit = (0..MAP_HEIGHT)
+            .flat_map(|y| (0..MAP_WIDTH).map(move |x| (x as f32 + 0.5, y as f32 + 0.5)))
+            .filter(|&(x, y)| map.read_tile(x, y) == Terrain::Doorway)
+            .min_by(|a, b| {
+                let a = distance(a.0 - self.player_x, a.1 - self.player_y);
+                let b = distance(b.0 - self.player_x, b.1 - self.player_y);
+                a.total_cmp(&b)
+            })?;
+        self.cue_from(map, exit.0, exit.1)
+    }
+
+    /// Kor sterkt og frå kva side ein lyd frå punktet (x, y) vert høyrd, der lyden vert
+    /// svakare med avstanden og når ein vegg er i vegen
+    fn cue_from(&self, map: &Map, x: f32, y: f32) -> Option<Cue> {
+        let source = self.ray_towards(map, x, y, Terrain::Open);
+        if source.distance >= CUE_RANGE {
+            return None;
+        }
+
+        let mut volume = 1.0 - source.distance / CUE_RANGE;
+        let column = self.raycast(map, self.player_angle + source.angle_diff);
+        if column.nearest_solid().is_some_and(|wall| wall.distance < source.distance - 0.5) {
+            volume *= CUE_OCCLUSION;
+        }
+
+        let pan = if fabsf(source.angle_diff) < CUE_CENTRE || fabsf(source.angle_diff) > PI - CUE_CENTRE {
+            Pan::Centre
+        } else if source.angle_diff > 0.0 {
+            Pan::Left
+        } else {
+            Pan::Right
+        };
+
+        Some(Cue { volume, pan })
+    }
+
+    /// Lyset på golvet som syner i rada `row` under horisonten i kolonna til `surface`
+    pub fn floor_light(&self, map: &Map, surface: &Surface, row: i32) -> f32 {
+        let (x, y) = self.surface_point(surface, row);
+        map.light(x, y, self.flicker)
+    }
+
+    /// Det golvet og taket i kolonna `column` har felles for alle radene, slik at
+    /// vinklane berre vert rekna ut ein gong per kolonne
+    pub fn surface(&self, column: usize, fisheye: bool) -> Surface {
+        let angle_diff = self.angle_diff(column);
+        let angle = self.player_angle + angle_diff;
+        Surface {
+            cos: cosf(angle),
+            sin: sinf(angle),
+            stretch: if fisheye { 1.0 } else { 1.0 / cosf(angle_diff) },
+            horizon: self.horizon(),
+            eye: self.eye_height(),
+        }
+    }
+
+    /// Punktet på golvet eller i taket som syner i rada `row` i kolonna til `surface`,
+    /// under eller over horisonten
+    pub fn surface_point(&self, surface: &Surface, row: i32) -> (f32, f32) {
+        // golvet i ei rad er like langt unna som botnen av ein vegg som sluttar der, og
+        // taket like langt unna som toppen av ein vegg
+        let below_horizon = row as f32 + 0.5 - surface.horizon;
+        let above = if below_horizon > 0.0 { surface.eye } else { 1.0 - surface.eye };
+        let distance = WALL_HEIGHT * above / fabsf(below_horizon) * surface.stretch;
+        (self.player_x + surface.cos * distance, self.player_y - surface.sin * distance)
+    }
+
+    /// Vinkelskilnaden til strålen i kolonna `column` på skjermen
+    pub fn angle_diff(&self, column: usize) -> f32 {
+        HALF_FOV - column as f32 * ANGLE_STEP
+    }
+
+    /// Gjev tilbake kolonna på skjermen som ei stråle med vinkelskilnaden svarar til
+    pub fn column(&self, angle_diff: f32) -> f32 {
+        (HALF_FOV - angle_diff) / ANGLE_STEP
+    }
+
+    /// Krysningane strålen i kolonna `column` har med gitteret langs kvar av dei to
+    /// gitterretningane, fram til første vegg, utan speglar og portalar
+    pub fn inspect(&self, map: &Map, column: usize) -> Inspection {
+        let angle_diff = self.angle_diff(column);
+        let beam = Beam {
+            x: self.player_x,
+            y: self.player_y,
+            angle: self.player_angle + angle_diff,
+            angle_diff,
+            travelled: 0.0,
+        };
+
+        Inspection {
+            horizontal: GridTrace::follow(self.horizontal_intersections(map, &beam), &beam),
+            vertical: GridTrace::follow(self.vertical_intersections(map, &beam), &beam),
+            beam,
+        }
+    }
+
+    /// Strålen rett fram midt på skjermen
+    pub fn centre_column(&self, map: &Map) -> Column {
+        self.raycast(map, self.player_angle)
+    }
+
+    /// Følgjer strålen gjennom gitteret og samlar veggane som kan synast, frå næraste og
+    /// bakover til ein vegg som skjuler alt bak seg
+    fn raycast(&self, map: &Map, angle: f32) -> Column {
+        self.stats.rays.set(self.stats.rays.get() + 1);
+        let mut column = Column::new(if self.noclip { MAX_FLY_HITS } else { MAX_HITS });
+        let mut beam = Beam {
+            x: self.player_x,
+            y: self.player_y,
+            angle,
+            angle_diff: angle - self.player_angle,
+            travelled: 0.0,
+        };
+        let mut bounces = 0;
+
+        // slope er den brattaste sikta over toppen av veggane så langt; det som ligg under
+        // denne sikta er skjult bak veggar som er nærare, men ein ser gjennom vindauge og gitter
+        let eye = self.eye_height();
+        let mut slope = f32::INFINITY;
+
+        // kvar runde følgjer strålen frå spelaren eller frå den siste spegelen
+        'beams: loop {
+            // utanfor kartet byrjar strålen der han går inn i kartet, og ruta der er det første
+            // han treff; eit fritt kamera kan stå der
+            let mut entry = None;
+            if !map.contains(beam.x, beam.y) {
+                let Some((entered, ray)) = beam.enter(map) else {
+                    return column;
                 };
+                beam = entered;
+                entry = Some(ray);
             }
-            if terrain != Terrain::Open {
-                return Ray {
-                    angle_diff: angle - self.player_angle,
-                    distance: 100.0,
-                    terrain: Terrain::Wall,
-                    orientation: Orientation::Vertical,
+
+            let mut crossings = Crossings {
+                horizontal: self.horizontal_intersections(map, &beam),
+                vertical: self.vertical_intersections(map, &beam),
+                push_wall: self.push_wall_intersection(map, &beam),
+            };
+            let reflection = self.reflection_of_player(&beam).filter(|_| bounces > 0);
+
+            // strålen kan starte i ei rute med eit tynt eller skrått veggstykke, eller under
+            // eit lågt tak; eit lite stykke fram, så veggen ikkje vert uendeleg høg
+            let mut start = Some(Ray {
+                angle_diff: beam.angle_diff,
+                distance: beam.travelled + 0.01,
+                exit_distance: beam.travelled + 0.01,
+                offset: 0.0,
+                terrain: map.read_tile(beam.x, beam.y),
+                orientation: Orientation::Vertical,
+                light: 1.0,
+            }).filter(|ray| ray.terrain.segment().is_some() || ray.terrain == Terrain::LowPassage).or(entry);
+
+            while let Some(mut ray) = start.take().or_else(|| crossings.next()) {
+                self.stats.steps.set(self.stats.steps.get() + 1);
+                if ray.terrain.is_walkable() {
+                    continue;
+                }
+
+                // strålen går ut av ruta der neste krysning er
+                ray.exit_distance = crossings.next_distance().min(ray.distance + 1.5);
+
+                // veggar som ikkje fyller ruta vert berre treft om strålen når veggstykket
+                if ray.terrain.segment().is_some() {
+                    match self.segment_intersection(map, &beam, &ray) {
+                        Some(hit) => ray = hit,
+                        None => continue,
+                    }
+                }
+
+                // portalar sender strålen vidare ut av den andre portalen utan å synast sjølve
+                if ray.terrain == Terrain::Portal {
+                    if let Some(portal) = beam.portal(map, &ray).filter(|_| bounces < MAX_BOUNCES) {
+                        column.reflection = column.reflection.or(reflection.filter(|image| image.distance < ray.distance));
+                        beam = beam.through(portal, &ray);
+                        bounces += 1;
+                        continue 'beams;
+                    }
+                    ray.terrain = Terrain::Wall;
+                }
+
+                // lyset på veggen er lyset i ruta rett framfor der strålen treff
+                let (x, y) = beam.point(ray.distance - 0.05);
+                ray.light = map.light(x, y, self.flicker);
+
+                // toppflata er synleg når spelaren ser ned på veggen, og når då lenger bak
+                let height = ray.terrain.height();
+                let top_slope = if eye > height {
+                    (eye - height) / ray.exit_distance
+                } else {
+                    (eye - height) / ray.distance
                 };
+                if top_slope < slope {
+                    // speglar sender strålen vidare, til han har sprette for mange gonger
+                    if ray.terrain == Terrain::Mirror {
+                        if bounces < MAX_BOUNCES {
+                            if !column.push(ray) {
+                                return column;
+                            }
+                            column.reflection = column.reflection.or(reflection.filter(|image| image.distance < ray.distance));
+                            beam = beam.reflect(&ray);
+                            bounces += 1;
+                            continue 'beams;
+                        }
+                        ray.terrain = Terrain::Wall;
+                    }
+
+                    if !ray.terrain.is_see_through() {
+                        slope = top_slope;
+                    }
+                    if !column.push(ray) {
+                        return column;
+                    }
+                }
+
+                // ingenting bak kan stikke opp over sikta når sjølv den høgaste veggen vert skjult;
+                // over veggane ser ein toppane lenger bak over dei næraste, så då held strålen fram
+                if eye <= MAX_WALL_HEIGHT && (eye - MAX_WALL_HEIGHT) / ray.distance >= slope {
+                    column.reflection = column.reflection.or(reflection);
+                    return column;
+                }
             }
 
-            // forleng strålen så lenge me ikkje har nådd ein vegg
-            next_x += dx;
-            next_y += dy;
+            // strålen gjekk ut av kartet, til dømes gjennom ei døropning
+            column.reflection = column.reflection.or(reflection);
+            column.outside = crossings.outside().map(|distance| Ray {
+                distance,
+                exit_distance: distance,
+                offset: 0.0,
+                terrain: Terrain::Open,
+                orientation: Orientation::Vertical,
+                angle_diff: beam.angle_diff,
+                light: 1.0,
+            });
+            return column;
         }
+    }
 
-        // gje tilbake avstanden fra (next_x, next_y) til spelarens posisjon
-        Ray {
-            angle_diff: angle - self.player_angle,
-            distance: distance(next_x, next_y),
-            terrain: Terrain::Wall,
+    /// Finn kvar strålen treff veggstykket i ruta han går gjennom mellom `ray.distance`
+    /// og `ray.exit_distance`
+    fn segment_intersection(&self, map: &Map, beam: &Beam, ray: &Ray) -> Option<Ray> {
+        // midt mellom inngangen og utgangen er strålen sikkert inne i ruta
+        let (dx, dy) = (cosf(beam.angle), -sinf(beam.angle));
+        let middle = (ray.distance + ray.exit_distance) / 2.0 - beam.travelled;
+        let segment = map.segment(beam.x + dx * middle, beam.y + dy * middle)?;
+
+        let (distance, offset) = segment.intersect(beam.x, beam.y, dx, dy)?;
+        let distance = beam.travelled + distance;
+        if distance < ray.distance || distance > ray.exit_distance {
+            return None;
+        }
+
+        Some(Ray {
+            distance,
+            exit_distance: distance,
+            offset,
+            orientation: segment.orientation,
+            ..*ray
+        })
+    }
+
+    /// Spegelbiletet av spelaren dersom ei spegla stråle går gjennom spelaren, der `offset`
+    /// seier kor langt bortover figuren strålen treff
+    fn reflection_of_player(&self, beam: &Beam) -> Option<Ray> {
+        let (dx, dy) = (cosf(beam.angle), -sinf(beam.angle));
+        let (to_x, to_y) = (self.player_x - beam.x, self.player_y - beam.y);
+
+        // along er kor langt fram langs strålen spelaren er, across kor langt til sida
+        let along = to_x * dx + to_y * dy;
+        let across = to_x * dy - to_y * dx;
+        if along <= 0.0 || fabsf(across) >= PLAYER_RADIUS {
+            return None;
+        }
+
+        Some(Ray {
+            angle_diff: beam.angle_diff,
+            distance: beam.travelled + along,
+            exit_distance: beam.travelled + along,
+            offset: (across / PLAYER_RADIUS + 1.0) / 2.0,
+            terrain: Terrain::Open,
             orientation: Orientation::Vertical,
+            light: 1.0,
+        })
+    }
+
+    /// Seier om spelaren går over eller for nær eit tynt eller skrått veggstykke på veg
+    /// frå `from` til `to`
+    fn crosses_segment(&self, map: &Map, from: (f32, f32), to: (f32, f32)) -> bool {
+        let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+        let length = sqrtf(dx * dx + dy * dy);
+        if length == 0.0 {
+            return false;
         }
+
+        // rørsla vert forlengd med WALL_MARGIN, slik at spelaren ikkje kjem heilt inntil veggen
+        let reach = (length + WALL_MARGIN) / length;
+        let end = (from.0 + dx * reach, from.1 + dy * reach);
+        [from, to, end].iter().any(|&(x, y)| {
+            map.segment(x, y)
+                .and_then(|segment| segment.intersect(from.0, from.1, dx, dy))
+                .is_some_and(|(t, _)| t <= reach)
+        })
     }
+}
 
-    pub fn get_rays(&self) -> [Option<Ray>; SCREEN_SIZE as usize] {
+/// Startpunktet og retninga til ei stråle, som kan ha sprette i speglar på vegen dit
+#[derive(Clone, Copy)]
+struct Beam {
+    x: f32,
+    y: f32,
+    angle: f32,
+    angle_diff: f32, // Vinkelskilnaden til kolonna strålen høyrer til
+    travelled: f32, // Kor langt strålen alt har gått før dette startpunktet
+}
 
-        let angle_step = FOV / SCREEN_SIZE as f32;
-        let initial_angle = self.player_angle + HALF_FOV;
+impl Beam {
+    /// Punktet strålen har kome til når han har gått `distance` i alt
+    fn point(&self, distance: f32) -> (f32, f32) {
+        let along = distance - self.travelled;
+        (self.x + cosf(self.angle) * along, self.y - sinf(self.angle) * along)
+    }
+
+    /// Portalen strålen går inn i når han treff ei portalrute der `ray` treff
+    fn portal(&self, map: &Map, ray: &Ray) -> Option<&'static Portal> {
+        let side = match ray.orientation {
+            Orientation::Vertical if cosf(self.angle) > 0.0 => Edge::West,
+            Orientation::Vertical => Edge::East,
+            Orientation::Horizontal if sinf(self.angle) < 0.0 => Edge::North,
+            Orientation::Horizontal => Edge::South,
+        };
+        let (x, y) = self.point(ray.distance + 0.001);
+        map.portal(x, y, side)
+    }
+
+    /// Strålen som held fram ut av den andre portalen etter at `ray` gjekk inn i `portal`
+    fn through(&self, portal: &Portal, ray: &Ray) -> Beam {
+        let (x, y) = self.point(ray.distance);
+        let (x, y, angle) = portal.transform(x, y, self.angle);
+
+        // startpunktet vert flytta eit lite steg fram, så det ligg framfor den andre portalen
+        Beam {
+            x: x + cosf(angle) * 0.001,
+            y: y - sinf(angle) * 0.001,
+            angle,
+            travelled: ray.distance,
+            ..*self
+        }
+    }
+
+    /// Strålen frå der denne strålen går inn i kartet, og ruta han går inn i, eller ingenting
+    /// om han aldri når kartet
+    fn enter(&self, map: &Map) -> Option<(Beam, Ray)> {
+        let (near_x, far_x) = slab(self.x, cosf(self.angle), 0.0, MAP_WIDTH as f32);
+        let (near_y, far_y) = slab(self.y, -sinf(self.angle), 0.0, MAP_HEIGHT as f32);
+        let near = near_x.max(near_y);
+        if near > far_x.min(far_y) || near <= 0.0 {
+            return None;
+        }
+
+        // startpunktet vert flytta eit lite steg inn, så det ligg inne i ruta i kanten
+        let (x, y) = self.point(self.travelled + near + 0.001);
+        let beam = Beam { x, y, travelled: self.travelled + near, ..*self };
+        let (orientation, offset) = if near_x > near_y {
+            (Orientation::Vertical, y - floorf(y))
+        } else {
+            (Orientation::Horizontal, x - floorf(x))
+        };
+        let ray = Ray {
+            angle_diff: self.angle_diff,
+            distance: beam.travelled,
+            exit_distance: beam.travelled,
+            offset,
+            terrain: map.read_map(x, y),
+            orientation,
+            light: 1.0,
+        };
+        Some((beam, ray))
+    }
 
-        let mut rays = [None; SCREEN_SIZE as usize];
+    /// Strålen som spretter tilbake frå spegelen `ray` treff
+    fn reflect(&self, ray: &Ray) -> Beam {
+        // startpunktet vert flytta eit lite steg tilbake, så det ligg framfor spegelen
+        let (x, y) = self.point(ray.distance - 0.001);
+        Beam {
+            x,
+            y,
+            angle: match ray.orientation {
+                Orientation::Vertical => PI - self.angle,
+                Orientation::Horizontal => -self.angle,
+            },
+            travelled: ray.distance,
+            ..*self
+        }
+    }
+}
 
-        for (idx, ray) in rays.iter_mut().enumerate() {
-            *ray = Some(self.raycast(initial_angle - idx as f32 * angle_step))
+/// Går langs gitterlinjene i éi retning og gjev tilbake kvar rute strålen kjem inn i,
+/// fram til strålen går ut av kartet
+struct GridWalk<'a> {
+    map: &'a Map,
+    origin_x: f32,
+    origin_y: f32,
+    next_x: f32,
+    next_y: f32,
+    dx: f32,
+    dy: f32,
+    angle_diff: f32,
+    travelled: f32,
+    orientation: Orientation,
+    steps: usize,
+}
+
+impl GridWalk<'_> {
+    /// Avstanden til krysninga strålen er komen til
+    fn next_distance(&self) -> f32 {
+        if self.steps == 0 {
+            return f32::INFINITY;
         }
+        self.travelled + distance(self.next_x, self.next_y)
+    }
 
-        rays
+    /// Seier om krysninga strålen er komen til ligg utanfor kartet
+    fn is_outside(&self) -> bool {
+        self.steps > 0 && !self.map.contains(self.next_x + self.origin_x, self.next_y + self.origin_y)
     }
+}
+
+impl Iterator for GridWalk<'_> {
+    type Item = Ray;
+
+    fn next(&mut self) -> Option<Ray> {
+        if self.steps == 0 || self.is_outside() {
+            return None;
+        }
+        self.steps -= 1;
 
-    fn raycast(&self, angle: f32) -> Ray {
-        let vertical_intersection = self.vertical_intersection(angle);
-        let horizontal_intersection = self.horizontal_intersection(angle);
+        // current_x og current_y er strålens noverande posisjon
+        let current_x = self.next_x + self.origin_x;
+        let current_y = self.next_y + self.origin_y;
+        let ray = Ray {
+            angle_diff: self.angle_diff,
+            distance: self.travelled + distance(self.next_x, self.next_y),
+            exit_distance: self.travelled + distance(self.next_x, self.next_y),
+            offset: match self.orientation {
+                Orientation::Horizontal => current_x - floorf(current_x),
+                Orientation::Vertical => current_y - floorf(current_y),
+            },
+            terrain: self.map.read_tile(current_x, current_y),
+            orientation: self.orientation,
+            light: 1.0,
+        };
 
-        if vertical_intersection.distance < horizontal_intersection.distance {
-            vertical_intersection
+        // forleng strålen
+        self.next_x += self.dx;
+        self.next_y += self.dy;
+
+        Some(ray)
+    }
+}
+
+/// Flettar krysningane frå begge gitterretningane og ein eventuell skyvevegg i rekkjefølgje
+/// etter avstand
+struct Crossings<'a> {
+    horizontal: GridWalk<'a>,
+    vertical: GridWalk<'a>,
+    push_wall: Option<Ray>,
+}
+
+impl<'a> Crossings<'a> {
+    /// Den av gitterretningane som har den næraste krysninga
+    fn nearest(&mut self) -> &mut GridWalk<'a> {
+        if self.vertical.next_distance() < self.horizontal.next_distance() {
+            &mut self.vertical
         } else {
-            horizontal_intersection
+            &mut self.horizontal
+        }
+    }
+
+    fn next_distance(&mut self) -> f32 {
+        let nearest = self.nearest().next_distance();
+        self.push_wall.map_or(nearest, |push_wall| push_wall.distance.min(nearest))
+    }
+
+    /// Avstanden til der strålen gjekk ut av kartet, om han gjorde det
+    fn outside(&mut self) -> Option<f32> {
+        let nearest = self.nearest();
+        nearest.is_outside().then(|| nearest.next_distance())
+    }
+}
+
+impl Iterator for Crossings<'_> {
+    type Item = Ray;
+
+    fn next(&mut self) -> Option<Ray> {
+        // når næraste krysning ligg utanfor kartet, er strålen ferdig
+        let nearest = self.nearest().next_distance();
+        match self.push_wall {
+            Some(push_wall) if push_wall.distance < nearest => self.push_wall.take(),
+            _ => self.nearest().next(),
+        }
+    }
+}
+
+/// Krysningane éi stråle har med gitteret, slik dei to gitterretningane ser dei kvar for seg
+pub struct Inspection {
+    beam: Beam,
+    pub horizontal: GridTrace,
+    pub vertical: GridTrace,
+}
+
+impl Inspection {
+    /// Punktet på kartet strålen har kome til etter `distance`
+    pub fn point(&self, distance: f32) -> (f32, f32) {
+        self.beam.point(distance)
+    }
+
+    /// Gitterretninga som fann den næraste veggen, og avstanden til han
+    pub fn winner(&self) -> Option<(Orientation, f32)> {
+        match (self.horizontal.hit, self.vertical.hit) {
+            (Some(horizontal), Some(vertical)) if vertical < horizontal => Some((Orientation::Vertical, vertical)),
+            (Some(horizontal), _) => Some((Orientation::Horizontal, horizontal)),
+            (None, Some(vertical)) => Some((Orientation::Vertical, vertical)),
+            (None, None) => None,
         }
     }
 }
 
+/// Krysningane langs éi gitterretning som (x, y) på kartet, avstanden til første vegg og
+/// kor langt retninga kom i alt
+pub struct GridTrace {
+    pub crossings: [Option<(f32, f32)>; MAX_INSPECTED],
+    pub hit: Option<f32>,
+    pub reach: f32,
+}
+
+impl GridTrace {
+    fn follow(walk: GridWalk, beam: &Beam) -> GridTrace {
+        let mut trace = GridTrace { crossings: [None; MAX_INSPECTED], hit: None, reach: 0.0 };
+        for (crossing, ray) in trace.crossings.iter_mut().zip(walk) {
+            *crossing = Some(beam.point(ray.distance));
+            trace.reach = ray.distance;
+            if !ray.terrain.is_walkable() {
+                trace.hit = Some(ray.distance);
+                break;
+            }
+        }
+        trace
+    }
+}
+
+/// Veggane ei stråle treff, frå næraste og bakover, kvar strålen eventuelt gjekk ut
+/// av kartet og kvar spelaren eventuelt syner att i ein spegel
+#[derive(Clone, Copy)]
+pub struct Column {
+    hits: [Option<Ray>; MAX_FLY_HITS],
+    limit: usize,
+    pub outside: Option<Ray>,
+    pub reflection: Option<Ray>,
+}
+
+impl Column {
+    /// Ei tom kolonne med plass til `limit` veggar
+    fn new(limit: usize) -> Column {
+        Column { hits: [None; MAX_FLY_HITS], limit, outside: None, reflection: None }
+    }
+
+    /// Legg til ein vegg bak dei andre, og seier om det er plass til fleire
+    fn push(&mut self, ray: Ray) -> bool {
+        if let Some(slot) = self.hits[..self.limit].iter_mut().find(|hit| hit.is_none()) {
+            *slot = Some(ray);
+        }
+        self.hits[self.limit - 1].is_none()
+    }
+
+    pub fn hits(&self) -> impl DoubleEndedIterator<Item = &Ray> {
+        self.hits.iter().flatten()
+    }
+
+    /// Den næraste veggen som ein ikkje kan sjå gjennom
+    pub fn nearest_solid(&self) -> Option<&Ray> {
+        self.hits().find(|ray| !ray.terrain.is_see_through())
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Ray {
     pub angle_diff: f32,
     pub distance: f32,
+    pub exit_distance: f32,
+    pub offset: f32,
     pub terrain: Terrain,
     pub orientation: Orientation,
+    pub light: f32,
 }
 
 impl Ray {
@@ -257,4 +1432,18 @@ impl Ray {
     pub fn wall_height_fisheye(&self) -> f32 {
         WALL_HEIGHT / self.distance
     }
-}
\ No newline at end of file
+
+    /// Gjev tilbake øvste og nedste skjermrad for veggen, der `height` er kor høg ein
+    /// vanleg vegg i same avstand er på skjermen, `eye` er augehøgda til spelaren og
+    /// `horizon` er skjermrada horisonten ligg på
+    pub fn wall_span(&self, height: f32, eye: f32, horizon: f32) -> (i32, i32) {
+        let bottom = horizon + height * eye;
+        let top = bottom - height * self.terrain.height();
+        (floorf(top) as i32, floorf(bottom) as i32)
+    }
+
+    /// Den same strålen der han går ut att av ruta han trefte
+    pub fn exit(&self) -> Ray {
+        Ray { distance: self.exit_distance, ..*self }
+    }
+}
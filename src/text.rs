@@ -0,0 +1,167 @@
+use crate::constants::FRAME_RATE;
+
+/// Språka teksten i spelet finst på
+#[derive(Clone, Copy, PartialEq)]
+pub enum Language {
+    Nynorsk,
+    English,
+}
+
+/// Tekstane i spelet, som vert slegne opp i `Language::get` for språket spelaren har valt
+#[derive(Clone, Copy, PartialEq)]
+pub enum Text {
+    LevelName,
+    Settings,
+    Music,
+    HeadBob,
+    Ceiling,
+    Minimap,
+    MinimapFixed,
+    MinimapRotating,
+    Language,
+    LanguageName,
+    On,
+    Off,
+    SettingsHelp,
+    Debug,
+    Noclip,
+    Speed,
+    TwoPlayers,
+    Player,
+    Wins,
+    SpeedNormal,
+    SpeedSlow,
+    SpeedPaused,
+    DebugOverlay,
+    DebugTrace,
+    MapZoom,
+    ZoomWhole,
+    ZoomMedium,
+    ZoomClose,
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Language {
+    /// Teksten på dette språket
+    pub fn get(&self, text: Text) -> &'static str {
+        let (nynorsk, english) = match text {
+            Text::LevelName => ("Kjellaren", "The Cellar"),
+            Text::Settings => ("Innstillingar", "Settings"),
+            Text::Music => ("Musikk", "Music"),
+            Text::HeadBob => ("Gynging", "Head bob"),
+            Text::Ceiling => ("Tak", "Ceiling"),
+            Text::Minimap => ("Minikart", "Minimap"),
+            Text::MinimapFixed => ("Fast", "Fixed"),
+            Text::MinimapRotating => ("Roterer", "Rotating"),
+            Text::Language => ("Språk", "Language"),
+            Text::LanguageName => ("Nynorsk", "English"),
+            Text::On => ("På", "On"),
+            Text::Off => ("Av", "Off"),
+            Text::SettingsHelp => ("X: byt  Z: kart", "X: change  Z: map"),
+            Text::Debug => ("Feilsøk", "Debug"),
+            Text::Noclip => ("Fritt kamera", "Free camera"),
+            Text::Speed => ("Fart", "Speed"),
+            Text::TwoPlayers => ("To spelarar", "Two players"),
+            Text::Player => ("Spelar ", "Player "),
+            Text::Wins => (" vann!", " wins!"),
+            Text::SpeedNormal => ("Vanleg", "Normal"),
+            Text::SpeedSlow => ("1/4", "1/4"),
+            Text::SpeedPaused => ("Pause", "Paused"),
+            Text::DebugOverlay => ("Skjerm", "Screen"),
+            Text::DebugTrace => ("Logg", "Log"),
+            Text::MapZoom => ("Kartzoom", "Map zoom"),
+            Text::ZoomWhole => ("Heile", "Whole"),
+            Text::ZoomMedium => ("Middels", "Medium"),
+            Text::ZoomClose => ("Nær", "Close"),
+            Text::North => ("N", "N"),
+            Text::East => ("A", "E"),
+            Text::South => ("S", "S"),
+            Text::West => ("V", "W"),
+        };
+        match self {
+            Language::Nynorsk => nynorsk,
+            Language::English => english,
+        }
+    }
+}
+
+/// Ein tekst som vert bygd opp i eit buffer på stakken, slik at tal kan skrivast ut utan
+/// å allokere; det som ikkje får plass vert kutta bort
+pub struct TextBuffer<const N: usize> {
+    bytes: [u8; N],
+    length: usize,
+}
+
+impl<const N: usize> TextBuffer<N> {
+    pub const fn new() -> TextBuffer<N> {
+        TextBuffer { bytes: [0; N], length: 0 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // bufferet inneheld berre heile teikn frå `push_str`
+        core::str::from_utf8(&self.bytes[..self.length]).unwrap_or("")
+    }
+
+    pub fn push_str(&mut self, text: &str) -> &mut Self {
+        // kutt ved siste heile teikn som får plass
+        let mut end = text.len().min(N - self.length);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.bytes[self.length..self.length + end].copy_from_slice(&text.as_bytes()[..end]);
+        self.length += end;
+        self
+    }
+
+    /// Skriv eit heiltal i titalssystemet
+    pub fn push_int(&mut self, value: i32) -> &mut Self {
+        if value < 0 {
+            self.push_str("-");
+        }
+
+        let mut digits = [0; 10];
+        let mut count = 0;
+        let mut rest = value.unsigned_abs();
+        loop {
+            digits[count] = b'0' + (rest % 10) as u8;
+            count += 1;
+            rest /= 10;
+            if rest == 0 {
+                break;
+            }
+        }
+        digits[..count].reverse();
+
+        self.push_str(core::str::from_utf8(&digits[..count]).unwrap_or(""))
+    }
+
+    /// Skriv eit flyttal med høgst `decimals` desimalar, så kort som ryu klarer å skrive det
+    pub fn push_float(&mut self, value: f32, decimals: usize) -> &mut Self {
+        let mut buffer = ryu::Buffer::new();
+        let formatted = buffer.format(value);
+
+        // tal med eksponent vert skrivne som dei er
+        let end = match formatted.find('.') {
+            Some(point) if !formatted.contains('e') => {
+                let end = (point + 1 + decimals).min(formatted.len());
+                if decimals == 0 { point } else { end }
+            }
+            _ => formatted.len(),
+        };
+        self.push_str(&formatted[..end])
+    }
+
+    /// Skriv tida etter `ticks` bilete som minutt og sekund, til dømes 2:05
+    pub fn push_time(&mut self, ticks: u32) -> &mut Self {
+        let seconds = ticks / FRAME_RATE;
+        self.push_int((seconds / 60) as i32).push_str(":");
+        if seconds % 60 < 10 {
+            self.push_str("0");
+        }
+        self.push_int((seconds % 60) as i32)
+    }
+}